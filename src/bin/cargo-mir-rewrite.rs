@@ -0,0 +1,144 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! `cargo mir-rewrite`: runs the mir-rewrite driver on every crate of a cargo workspace.
+//!
+//! The driver is installed as RUSTC_WORKSPACE_WRAPPER, so cargo only hands it workspace
+//! members; dependencies are compiled by plain rustc. `--analysis-*` options are forwarded
+//! to the driver through an environment variable, everything else goes to `cargo check`.
+//!
+//! The report is a side effect of compiling, which cargo knows nothing of: it would consider
+//! workspace members fresh after a first run, even with other analysis options, and skip the
+//! driver. So the workspace members are cleaned before every run.
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+/// Must agree with ANALYSIS_ARGS_ENV in the driver
+const ANALYSIS_ARGS_ENV: &str = "MIR_REWRITE_ANALYSIS_ARGS";
+
+/// The driver binary, installed next to this one
+fn driver_path() -> PathBuf {
+    let mut path = env::current_exe().expect("current executable path is unavailable");
+    path.set_file_name(if cfg!(windows) {
+        "mir-rewrite.exe"
+    } else {
+        "mir-rewrite"
+    });
+    path
+}
+
+/// Library directory of the active toolchain, which the driver links against dynamically
+fn toolchain_lib_path() -> PathBuf {
+    let output = process::Command::new("rustc")
+        .args(["--print", "sysroot"])
+        .output()
+        .expect("failed to run rustc --print sysroot");
+    let sysroot = String::from_utf8(output.stdout).expect("sysroot is not valid UTF-8");
+    PathBuf::from(sysroot.trim()).join("lib")
+}
+
+/// The workspace as `cargo metadata` describes it
+fn cargo_metadata(cargo: &str, manifest_args: &[String]) -> serde_json::Value {
+    let output = process::Command::new(cargo)
+        .args(["metadata", "--no-deps", "--format-version", "1"])
+        .args(manifest_args)
+        .output()
+        .expect("failed to run cargo metadata");
+    if !output.status.success() {
+        process::exit(output.status.code().unwrap_or(1));
+    }
+    serde_json::from_slice(&output.stdout).expect("cargo metadata printed invalid JSON")
+}
+
+/// Names of the workspace members
+fn workspace_members(metadata: &serde_json::Value) -> Vec<String> {
+    metadata["packages"]
+        .as_array()
+        .expect("cargo metadata lists no packages")
+        .iter()
+        .filter_map(|package| package["name"].as_str().map(str::to_owned))
+        .collect()
+}
+
+fn main() {
+    let mut cargo_args = Vec::new();
+    let mut analysis_args = Vec::new();
+
+    // cargo invokes us as `cargo-mir-rewrite mir-rewrite <args>`
+    for arg in env::args().skip(1).skip_while(|arg| arg == "mir-rewrite") {
+        if arg.starts_with("--analysis") {
+            analysis_args.push(arg);
+        } else {
+            cargo_args.push(arg);
+        }
+    }
+
+    let library_path_var = if cfg!(target_os = "macos") {
+        "DYLD_FALLBACK_LIBRARY_PATH"
+    } else {
+        "LD_LIBRARY_PATH"
+    };
+    let mut library_paths = vec![toolchain_lib_path()];
+    if let Some(existing) = env::var_os(library_path_var) {
+        library_paths.extend(env::split_paths(&existing));
+    }
+
+    let cargo_path = env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+
+    // `cargo clean` and `cargo metadata` must look at the same workspace as `cargo check`
+    let manifest_args: Vec<String> = cargo_args
+        .iter()
+        .zip(cargo_args.iter().skip(1))
+        .filter(|(flag, _)| *flag == "--manifest-path")
+        .flat_map(|(flag, path)| [flag.clone(), path.clone()])
+        .chain(
+            cargo_args
+                .iter()
+                .filter(|arg| arg.starts_with("--manifest-path="))
+                .cloned(),
+        )
+        .collect();
+    let metadata = cargo_metadata(&cargo_path, &manifest_args);
+
+    // Our artifacts are kept apart from regular builds, wherever the workspace puts those,
+    //  so that cleaning them below leaves the user's own builds alone
+    let target_dir = PathBuf::from(
+        metadata["target_directory"]
+            .as_str()
+            .expect("cargo metadata gives no target directory"),
+    )
+    .join("mir-rewrite");
+
+    let mut clean = process::Command::new(&cargo_path);
+    clean
+        .arg("clean")
+        .args(&manifest_args)
+        .env("CARGO_TARGET_DIR", &target_dir);
+    for member in workspace_members(&metadata) {
+        clean.args(["-p", &member]);
+    }
+    let status = clean.status().expect("failed to run cargo clean");
+    if !status.success() {
+        process::exit(status.code().unwrap_or(1));
+    }
+
+    let mut cargo = process::Command::new(&cargo_path);
+    cargo
+        .arg("check")
+        .args(&cargo_args)
+        .env("RUSTC_WORKSPACE_WRAPPER", driver_path())
+        .env(ANALYSIS_ARGS_ENV, analysis_args.join("\x1f"))
+        .env(
+            library_path_var,
+            env::join_paths(library_paths).expect("invalid library path"),
+        )
+        .env("CARGO_TARGET_DIR", &target_dir);
+
+    let status = cargo.status().expect("failed to run cargo");
+    process::exit(status.code().unwrap_or(1));
+}
@@ -0,0 +1,246 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Capabilities mode: for every location of every function, and every place reachable from
+//! the function's user variables, which of move-out, mutable borrow, shared borrow and read
+//! borrowck allows there.
+//!
+//! A first compilation only records the locations and places of each body. The tests of a
+//! function are then given to one child compilation, which packs those whose verdicts can be
+//! told apart into the body and compiles each of the others on its own, i.e. those which may
+//! interfere with the others, e.g. by keeping the loans of a dead reference alive. Move-outs
+//! and mutable borrows, whose place errors the Polonius facts do not express, are packed too,
+//! but cost one more compilation per place.
+
+use crate::config::{self, PlaceElemSpec, PlaceSpec, TestSpec};
+use crate::havoc;
+use crate::report::Verdict;
+use crate::runner;
+use crate::TestKind;
+use rustc_hir::def::DefKind;
+use rustc_middle::mir::{Body, Location};
+use rustc_middle::ty::{self, Ty, TyCtxt};
+use rustc_span::def_id::LocalDefId;
+use rustc_target::abi::FieldIdx;
+use serde::Serialize;
+use std::fs;
+use std::sync::{LazyLock, Mutex};
+
+/// How many projections deep places are followed from a user variable
+const MAX_PROJECTION_DEPTH: usize = 3;
+
+/// The tests whose verdicts make up a place's capabilities
+const CAPABILITY_TESTS: [TestKind; 4] = [
+    TestKind::MoveOut,
+    TestKind::MutBorrow,
+    TestKind::SharedBorrow,
+    TestKind::Read,
+];
+
+/// Locations and places of a body, recorded by the first compilation
+#[derive(Debug)]
+struct BodyPlaces {
+    function: String,
+    locations: Vec<Location>,
+    places: Vec<PlaceSpec>,
+}
+
+static BODIES: LazyLock<Mutex<Vec<BodyPlaces>>> = LazyLock::new(Default::default);
+
+/// Appends the places reachable from place, which has type ty, to places
+fn collect_places<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    place: PlaceSpec,
+    ty: Ty<'tcx>,
+    places: &mut Vec<PlaceSpec>,
+) {
+    places.push(place.clone());
+    if place.projection.len() == MAX_PROJECTION_DEPTH {
+        return;
+    }
+    let project = |elem: PlaceElemSpec| {
+        let mut projection = place.projection.clone();
+        projection.push(elem);
+        PlaceSpec {
+            local: place.local,
+            projection,
+        }
+    };
+
+    match ty.kind() {
+        ty::Ref(_, pointee, _) => {
+            collect_places(tcx, project(PlaceElemSpec::Deref), *pointee, places)
+        }
+        ty::Adt(..) if ty.is_box() => {
+            collect_places(tcx, project(PlaceElemSpec::Deref), ty.boxed_ty(), places)
+        }
+        ty::Adt(adt, args) if adt.is_struct() => {
+            for (field, field_def) in adt.non_enum_variant().fields.iter_enumerated() {
+                collect_places(
+                    tcx,
+                    project(PlaceElemSpec::Field(field)),
+                    field_def.ty(tcx, args),
+                    places,
+                );
+            }
+        }
+        ty::Tuple(tys) => {
+            for (index, field_ty) in tys.iter().enumerate() {
+                collect_places(
+                    tcx,
+                    project(PlaceElemSpec::Field(FieldIdx::from_usize(index))),
+                    field_ty,
+                    places,
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Records the locations and user places of a body, if it is a function's
+pub(crate) fn record_body<'tcx>(tcx: TyCtxt<'tcx>, def_id: LocalDefId, body: &Body<'tcx>) {
+    if !matches!(tcx.def_kind(def_id), DefKind::Fn | DefKind::AssocFn)
+        || def_id.to_def_id() == havoc::havoc_def_id(tcx)
+    {
+        return;
+    }
+
+    // Cleanup blocks only run while unwinding, and cannot hold a test's split
+    let locations = body
+        .basic_blocks
+        .iter_enumerated()
+        .filter(|(_, data)| !data.is_cleanup)
+        .flat_map(|(block, data)| {
+            (0..=data.statements.len()).map(move |statement_index| Location {
+                block,
+                statement_index,
+            })
+        })
+        .collect();
+
+    let mut places = vec![];
+    for (local, decl) in body.local_decls.iter_enumerated() {
+        if decl.is_user_variable() {
+            let place = PlaceSpec {
+                local,
+                projection: vec![],
+            };
+            collect_places(tcx, place, decl.ty, &mut places);
+        }
+    }
+
+    BODIES.lock().unwrap().push(BodyPlaces {
+        function: tcx.def_path_str(def_id.to_def_id()),
+        locations,
+        places,
+    });
+}
+
+#[derive(Debug, Serialize)]
+struct PlaceCapabilities {
+    location: String,
+    place: String,
+    /// None where the child compilation crashed
+    move_out: Option<Verdict>,
+    mut_borrow: Option<Verdict>,
+    shared_borrow: Option<Verdict>,
+    read: Option<Verdict>,
+    /// Set when every test on the place was rejected
+    blocked: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct FunctionCapabilities {
+    function: String,
+    places: Vec<PlaceCapabilities>,
+}
+
+fn print_table(function: &FunctionCapabilities) {
+    let cell = |verdict: Option<Verdict>| match verdict {
+        Some(Verdict::Accepted) => "yes",
+        Some(Verdict::Rejected) => "no",
+        Some(Verdict::Uninitialised) => "uninit",
+        None => "?",
+    };
+    println!("[info] capabilities of {}", function.function);
+    println!(
+        "{:<12} {:<16} {:<8} {:<10} {:<13} {:<5}",
+        "location", "place", "move-out", "mut-borrow", "shared-borrow", "read"
+    );
+    for place in function.places.iter() {
+        println!(
+            "{:<12} {:<16} {:<8} {:<10} {:<13} {:<5}{}",
+            place.location,
+            place.place,
+            cell(place.move_out),
+            cell(place.mut_borrow),
+            cell(place.shared_borrow),
+            cell(place.read),
+            if place.blocked { " blocked" } else { "" }
+        );
+    }
+}
+
+/// Runs the capability tests of every body the first compilation recorded
+pub(crate) fn run(rustc_args: &[String]) {
+    let bodies = std::mem::take(&mut *BODIES.lock().unwrap());
+    let mut functions = vec![];
+    let engine = config::config().engine;
+    for body in bodies.into_iter() {
+        let mut specs = vec![];
+        for location in body.locations.iter() {
+            for place in body.places.iter() {
+                specs.extend(CAPABILITY_TESTS.iter().map(|kind| TestSpec {
+                    function: body.function.clone(),
+                    location: *location,
+                    place: place.clone(),
+                    kind: *kind,
+                    outlives: None,
+                    activation: None,
+                }));
+            }
+        }
+
+        let verdicts: Vec<_> = runner::run_tests(rustc_args, &specs, engine)
+            .into_iter()
+            .map(|test| test.map(|test| test.verdict))
+            .collect();
+
+        let places = specs
+            .chunks(CAPABILITY_TESTS.len())
+            .zip(verdicts.chunks(CAPABILITY_TESTS.len()))
+            .map(|(specs, verdicts)| PlaceCapabilities {
+                location: format!("{:?}", specs[0].location),
+                place: specs[0].place.to_string(),
+                move_out: verdicts[0],
+                mut_borrow: verdicts[1],
+                shared_borrow: verdicts[2],
+                read: verdicts[3],
+                blocked: verdicts
+                    .iter()
+                    .all(|verdict| *verdict == Some(Verdict::Rejected)),
+            })
+            .collect();
+        let function = FunctionCapabilities {
+            function: body.function,
+            places,
+        };
+        print_table(&function);
+        functions.push(function);
+    }
+
+    if let Some(path) = &config::config().report {
+        let path = if path.is_dir() {
+            path.join("capabilities.json")
+        } else {
+            path.to_owned()
+        };
+        let file = fs::File::create(&path).expect("could not create the report file");
+        serde_json::to_writer_pretty(file, &functions).expect("could not write the report");
+        println!("[info] report written to {}", path.display());
+    }
+}
@@ -0,0 +1,435 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Removal of forged code once the borrow checker has seen it.
+//!
+//! Test blocks, forged locals and calls to the havoc helper only exist to ask borrowck
+//! questions; they must never reach optimisation or codegen. The rewrite in `mir_built`
+//! registers what it forged for each body here, and the `mir_drops_elaborated_and_const_checked`
+//! override strips it from the body handed on to the rest of the compiler.
+//! Ghost locals and statements on the real path are forged code too, and go the same way.
+//! Stripping merges the blocks split for forged code back together, and checks that the
+//! stripped body has the control flow of the unmodified one.
+
+use rustc_data_structures::steal::Steal;
+use rustc_hash::{FxHashMap, FxHashSet};
+use rustc_index::IndexVec;
+use rustc_middle::mir::traversal;
+use rustc_middle::mir::visit::{MutVisitor, PlaceContext, TyContext, Visitor};
+use rustc_middle::mir::{
+    BasicBlock, Body, Local, Location, Statement, Terminator, TerminatorKind, START_BLOCK,
+};
+use rustc_middle::ty::{self, Ty, TyCtxt};
+use rustc_span::def_id::{DefId, LocalDefId};
+use rustc_span::Span;
+use std::mem;
+use std::sync::{LazyLock, Mutex};
+
+/// Everything the rewrite forged in one body
+#[derive(Debug, Default)]
+pub(crate) struct ForgedItems {
+    /// Locals allocated by the BodyModifier
+    pub locals: FxHashSet<Local>,
+
+    /// The ghost locals among them, whose statements lie on the real path
+    pub ghost_locals: FxHashSet<Local>,
+
+    /// DefIds which only forged code calls, such as the havoc helper
+    pub def_ids: FxHashSet<DefId>,
+
+    /// Types of the locals in the unmodified body, used to check the stripped body against it
+    pub original_local_tys: Vec<String>,
+
+    /// Control flow of the unmodified body, used to check the stripped body against it
+    pub original_control_flow: ControlFlow,
+}
+
+/// A point where control enters or leaves the code of a body: its entry, a call, or a return
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum ControlPoint {
+    Entry,
+    /// The callee, if it is a function item, and the span of the call
+    Call(Option<DefId>, Span),
+    Return,
+}
+
+/// The control points of a body reachable from its entry, each with the control points which
+/// can come next after it
+///
+/// The passes between `mir_built` and drop elaboration rewrite drops, merge and add blocks,
+/// lower intrinsics and replace FalseEdges by a Goto to their real target, but keep the other
+/// calls and the returns, and the order they can run in. Generators are the exception, as
+/// their state transform turns yields into returns.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct ControlFlow(FxHashMap<ControlPoint, FxHashSet<ControlPoint>>);
+
+/// Successors of a terminator on the normal path, i.e. leaving out unwinding and the
+/// imaginary targets of FalseEdges and FalseUnwinds
+fn normal_successors(kind: &TerminatorKind<'_>) -> Vec<BasicBlock> {
+    match kind {
+        TerminatorKind::Goto { target }
+        | TerminatorKind::Drop { target, .. }
+        | TerminatorKind::Assert { target, .. } => vec![*target],
+        TerminatorKind::FalseEdge { real_target, .. }
+        | TerminatorKind::FalseUnwind { real_target, .. } => vec![*real_target],
+        TerminatorKind::SwitchInt { targets, .. } => targets.all_targets().to_vec(),
+        TerminatorKind::Yield { resume, drop, .. } => {
+            std::iter::once(*resume).chain(*drop).collect()
+        }
+        TerminatorKind::InlineAsm { destination, .. } => destination.iter().cloned().collect(),
+        TerminatorKind::Call { target, .. } => target.iter().cloned().collect(),
+        _ => vec![],
+    }
+}
+
+impl ControlFlow {
+    pub fn of<'tcx>(tcx: TyCtxt<'tcx>, body: &Body<'tcx>) -> Self {
+        let point_at = |block: BasicBlock| match &body.basic_blocks[block].terminator().kind {
+            TerminatorKind::Call { func, fn_span, .. } => {
+                let callee = match func.ty(body, tcx).kind() {
+                    ty::FnDef(def_id, _) => Some(*def_id),
+                    _ => None,
+                };
+                (!callee.is_some_and(|def_id| tcx.is_intrinsic(def_id)))
+                    .then_some(ControlPoint::Call(callee, *fn_span))
+            }
+            TerminatorKind::Return => Some(ControlPoint::Return),
+            _ => None,
+        };
+        // The control points first reached from a block, with the blocks they end
+        let first_points = |start: BasicBlock| {
+            let mut points = vec![];
+            let mut visited = FxHashSet::default();
+            let mut stack = vec![start];
+            while let Some(block) = stack.pop() {
+                if !visited.insert(block) {
+                    continue;
+                }
+                match point_at(block) {
+                    Some(point) => points.push((block, point)),
+                    None => stack.extend(normal_successors(
+                        &body.basic_blocks[block].terminator().kind,
+                    )),
+                }
+            }
+            points
+        };
+
+        let mut flow: FxHashMap<ControlPoint, FxHashSet<ControlPoint>> = FxHashMap::default();
+        let mut visited_calls = FxHashSet::default();
+        let mut queue = vec![(ControlPoint::Entry, Some(START_BLOCK))];
+        while let Some((point, next)) = queue.pop() {
+            let followers = flow.entry(point).or_default();
+            let Some(next) = next else {
+                continue;
+            };
+            for (block, follower) in first_points(next) {
+                followers.insert(follower.clone());
+                if let TerminatorKind::Call { target, .. } =
+                    &body.basic_blocks[block].terminator().kind
+                {
+                    if visited_calls.insert(block) {
+                        queue.push((follower, *target));
+                    }
+                }
+            }
+        }
+        ControlFlow(flow)
+    }
+}
+
+/// Forged items of every rewritten body, waiting to be stripped
+static FORGED_ITEMS: LazyLock<Mutex<FxHashMap<LocalDefId, ForgedItems>>> =
+    LazyLock::new(Default::default);
+
+pub(crate) fn register_forged_items(def_id: LocalDefId, items: ForgedItems) {
+    let previous = FORGED_ITEMS.lock().unwrap().insert(def_id, items);
+    assert!(previous.is_none(), "{:?} was rewritten twice", def_id);
+}
+
+/// Runs f on the forged items of a body, if it was rewritten
+pub(crate) fn with_forged_items<R>(
+    def_id: LocalDefId,
+    f: impl FnOnce(&ForgedItems) -> R,
+) -> Option<R> {
+    FORGED_ITEMS.lock().unwrap().get(&def_id).map(f)
+}
+
+fn take_forged_items(def_id: LocalDefId) -> Option<ForgedItems> {
+    FORGED_ITEMS.lock().unwrap().remove(&def_id)
+}
+
+/// Finds any mention of a forged local or DefId
+struct ForgedUses<'a> {
+    forged: &'a ForgedItems,
+    /// Set when mentions of ghost locals do not count
+    skip_ghost: bool,
+    found: bool,
+}
+
+impl<'a, 'tcx> Visitor<'tcx> for ForgedUses<'a> {
+    fn visit_local(&mut self, local: Local, _context: PlaceContext, _location: Location) {
+        self.found |= self.forged.locals.contains(&local)
+            && !(self.skip_ghost && self.forged.ghost_locals.contains(&local));
+    }
+
+    fn visit_ty(&mut self, ty: Ty<'tcx>, _context: TyContext) {
+        if let ty::FnDef(def_id, _) = ty.kind() {
+            self.found |= self.forged.def_ids.contains(def_id);
+        }
+    }
+}
+
+impl ForgedItems {
+    /// Does the statement mention a forged local or DefId?
+    pub fn is_mentioned_in_statement(
+        &self,
+        statement: &Statement<'_>,
+        location: Location,
+    ) -> bool {
+        let mut uses = ForgedUses {
+            forged: self,
+            skip_ghost: false,
+            found: false,
+        };
+        uses.visit_statement(statement, location);
+        uses.found
+    }
+
+    fn is_mentioned_in_terminator(&self, terminator: &Terminator<'_>, location: Location) -> bool {
+        let mut uses = ForgedUses {
+            forged: self,
+            skip_ghost: false,
+            found: false,
+        };
+        uses.visit_terminator(terminator, location);
+        uses.found
+    }
+
+    /// Does the statement or terminator at this location mention anything forged?
+    pub fn is_mentioned_at(&self, body: &Body<'_>, location: Location) -> bool {
+        let data = &body.basic_blocks[location.block];
+        match data.statements.get(location.statement_index) {
+            Some(statement) => self.is_mentioned_in_statement(statement, location),
+            None => self.is_mentioned_in_terminator(data.terminator(), location),
+        }
+    }
+
+    /// Does the location belong to forged code, either mentioning something forged itself or
+    /// lying in a test or approximator block?
+    ///
+    /// Those blocks are only entered through a FalseEdge, so SimplifyCfg never merges them
+    /// with real ones, and every one of them mentions a forged local other than a ghost one.
+    /// This also covers the forged statements and terminators in them which only mention real
+    /// places, such as the StorageDead or Drop of a tested place. Ghost statements lie on the
+    /// real path, and are merged into the real blocks around them, so only count themselves.
+    pub fn owns(&self, body: &Body<'_>, location: Location) -> bool {
+        self.is_mentioned_at(body, location) || self.is_forged_block(body, location.block)
+    }
+
+    /// Is the block a test or approximator block, i.e. does it mention a forged local which
+    /// is not a ghost one?
+    pub fn is_forged_block(&self, body: &Body<'_>, block: BasicBlock) -> bool {
+        let mut uses = ForgedUses {
+            forged: self,
+            skip_ghost: true,
+            found: false,
+        };
+        uses.visit_basic_block_data(block, &body.basic_blocks[block]);
+        uses.found
+    }
+}
+
+/// Renames locals after the forged ones have been removed from the declarations
+struct LocalRenumberer<'tcx> {
+    tcx: TyCtxt<'tcx>,
+    map: IndexVec<Local, Option<Local>>,
+}
+
+impl<'tcx> MutVisitor<'tcx> for LocalRenumberer<'tcx> {
+    fn tcx(&self) -> TyCtxt<'tcx> {
+        self.tcx
+    }
+
+    fn visit_local(&mut self, local: &mut Local, _context: PlaceContext, _location: Location) {
+        *local = self.map[*local].expect("forged local is still in use");
+    }
+}
+
+/// Removes every forged statement and bypasses every forged terminator
+fn strip_forged_code<'tcx>(body: &mut Body<'tcx>, forged: &ForgedItems) {
+    for (block, data) in body.basic_blocks.as_mut().iter_enumerated_mut() {
+        let mut statement_index = 0;
+        data.statements.retain(|statement| {
+            let location = Location {
+                block,
+                statement_index,
+            };
+            statement_index += 1;
+            !forged.is_mentioned_in_statement(statement, location)
+        });
+
+        let location = Location {
+            block,
+            statement_index: data.statements.len(),
+        };
+        let terminator = data.terminator_mut();
+        if forged.is_mentioned_in_terminator(terminator, location) {
+            terminator.kind = match terminator.kind {
+                TerminatorKind::Call {
+                    target: Some(target),
+                    ..
+                }
+                | TerminatorKind::Drop { target, .. } => TerminatorKind::Goto { target },
+                _ => TerminatorKind::Unreachable,
+            };
+        }
+    }
+}
+
+/// Merges every block into the block jumping to it, where that is its only predecessor
+///
+/// This joins the blocks split for ghost code and for the forged calls of approximators
+/// back together. The merged blocks are left empty and unreachable.
+fn merge_split_blocks(body: &mut Body<'_>) {
+    let predecessor_counts: Vec<usize> = body
+        .basic_blocks
+        .predecessors()
+        .iter()
+        .map(|predecessors| predecessors.len())
+        .collect();
+    let blocks = body.basic_blocks_mut();
+    for block in blocks.indices() {
+        while let TerminatorKind::Goto { target } = blocks[block].terminator().kind {
+            if target == block
+                || target == START_BLOCK
+                || predecessor_counts[target.index()] != 1
+                || blocks[target].is_cleanup != blocks[block].is_cleanup
+            {
+                break;
+            }
+            let source_info = blocks[target].terminator().source_info;
+            let statements = mem::take(&mut blocks[target].statements);
+            let terminator = mem::replace(
+                blocks[target].terminator_mut(),
+                Terminator {
+                    source_info,
+                    kind: TerminatorKind::Unreachable,
+                },
+            );
+            blocks[block].statements.extend(statements);
+            blocks[block].terminator = Some(terminator);
+        }
+    }
+}
+
+/// Removes blocks which can no longer be reached, renumbering the rest
+///
+/// Test and approximator blocks are only reachable through the imaginary target of a
+/// FalseEdge; CleanupPostBorrowck has already replaced those by a Goto to the real target.
+fn remove_unreachable_blocks(body: &mut Body<'_>) {
+    let reachable: FxHashSet<BasicBlock> =
+        traversal::reachable(body).map(|(block, _)| block).collect();
+    if reachable.len() == body.basic_blocks.len() {
+        return;
+    }
+
+    let mut replacements: Vec<Option<BasicBlock>> = vec![None; body.basic_blocks.len()];
+    let mut kept: IndexVec<BasicBlock, _> = IndexVec::new();
+    for (block, data) in mem::take(body.basic_blocks_mut()).into_iter_enumerated() {
+        if reachable.contains(&block) {
+            replacements[block.index()] = Some(kept.push(data));
+        }
+    }
+
+    for data in kept.iter_mut() {
+        for target in data.terminator_mut().successors_mut() {
+            *target = replacements[target.index()].unwrap();
+        }
+    }
+    *body.basic_blocks_mut() = kept;
+}
+
+/// Removes the forged local declarations, shifting down any locals added after them
+fn remove_forged_locals<'tcx>(tcx: TyCtxt<'tcx>, body: &mut Body<'tcx>, forged: &ForgedItems) {
+    let mut map: IndexVec<Local, Option<Local>> =
+        IndexVec::from_elem_n(None, body.local_decls.len());
+    let mut next = Local::from_u32(0);
+    for local in body.local_decls.indices() {
+        if !forged.locals.contains(&local) {
+            map[local] = Some(next);
+            next = next + 1;
+        }
+    }
+
+    body.local_decls = mem::take(&mut body.local_decls)
+        .into_iter_enumerated()
+        .filter(|(local, _)| !forged.locals.contains(local))
+        .map(|(_, decl)| decl)
+        .collect();
+    LocalRenumberer { tcx, map }.visit_body(body);
+}
+
+/// Checks that the locals of the unmodified body kept their indices and types once the
+/// forged ones are removed
+fn verify_original_locals(body: &Body<'_>, forged: &ForgedItems, def_id: LocalDefId) {
+    let original_local_tys = body
+        .local_decls
+        .iter()
+        .take(forged.original_local_tys.len())
+        .map(|decl| format!("{:?}", decl.ty));
+    assert!(
+        original_local_tys.eq(forged.original_local_tys.iter().cloned()),
+        "locals of {:?} differ from the unmodified body after stripping",
+        def_id
+    );
+}
+
+#[allow(clippy::needless_lifetimes)]
+pub(crate) fn mir_drops_elaborated_and_const_checked<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    def_id: LocalDefId,
+) -> &'tcx Steal<Body<'tcx>> {
+    // Borrowck runs on the modified body as part of the default provider
+    let default_ptr =
+        rustc_interface::DEFAULT_QUERY_PROVIDERS.mir_drops_elaborated_and_const_checked;
+    let result = default_ptr(tcx, def_id);
+    let Some(forged) = take_forged_items(def_id) else {
+        return result;
+    };
+
+    let mut body = result.steal();
+    strip_forged_code(&mut body, &forged);
+    merge_split_blocks(&mut body);
+    remove_unreachable_blocks(&mut body);
+
+    // Nothing forged may survive the first two steps; after renumbering the forged
+    //  locals' indices may belong to locals added by drop elaboration
+    let mut uses = ForgedUses {
+        forged: &forged,
+        skip_ghost: false,
+        found: false,
+    };
+    uses.visit_body(&body);
+    assert!(
+        !uses.found,
+        "forged code survived stripping in {:?}",
+        def_id
+    );
+
+    remove_forged_locals(tcx, &mut body, &forged);
+    verify_original_locals(&body, &forged, def_id);
+    // The blocks themselves have changed since `mir_built`, so only their control flow can be
+    //  compared with the unmodified body's
+    assert!(
+        body.generator_kind().is_some()
+            || ControlFlow::of(tcx, &body) == forged.original_control_flow,
+        "control flow of {:?} differs from the unmodified body after stripping",
+        def_id
+    );
+
+    tcx.alloc_steal_mir(body)
+}
@@ -0,0 +1,698 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Options passed to the driver as `--analysis-*` arguments.
+//!
+//! Query providers are plain function pointers, so the parsed options live in a global
+//! which is set once before the compiler starts.
+
+use crate::{ForgedUnwind, TestKind};
+use rustc_middle::mir::{BasicBlock, Local, LocalDecls, Location, Place};
+use rustc_middle::ty::{self, TyCtxt};
+use rustc_target::abi::FieldIdx;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Borrow checker used to judge the rewritten bodies
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BorrowckEngine {
+    /// Non-lexical lifetimes, the default in rustc
+    Nll,
+    /// Legacy Polonius (`-Zpolonius`) with its full, location-sensitive analysis
+    Polonius,
+    /// Polonius restricted to its location-insensitive pre-pass, which over-approximates errors
+    PoloniusLocationInsensitive,
+}
+
+impl BorrowckEngine {
+    /// Compiler flags selecting this engine
+    pub fn compiler_args(&self) -> Vec<String> {
+        match self {
+            BorrowckEngine::Nll => vec![],
+            BorrowckEngine::Polonius | BorrowckEngine::PoloniusLocationInsensitive => {
+                vec!["-Zpolonius".to_owned()]
+            }
+        }
+    }
+
+    /// Algorithm rustc_borrowck reads from POLONIUS_ALGORITHM when computing Polonius output
+    pub fn polonius_algorithm(&self) -> &'static str {
+        match self {
+            BorrowckEngine::Nll | BorrowckEngine::Polonius => "Hybrid",
+            BorrowckEngine::PoloniusLocationInsensitive => "LocationInsensitive",
+        }
+    }
+}
+
+impl FromStr for BorrowckEngine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nll" => Ok(BorrowckEngine::Nll),
+            "polonius" => Ok(BorrowckEngine::Polonius),
+            "polonius-location-insensitive" => Ok(BorrowckEngine::PoloniusLocationInsensitive),
+            _ => Err(format!("unknown borrowck engine {s:?}")),
+        }
+    }
+}
+
+impl fmt::Display for BorrowckEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BorrowckEngine::Nll => write!(f, "nll"),
+            BorrowckEngine::Polonius => write!(f, "polonius"),
+            BorrowckEngine::PoloniusLocationInsensitive => {
+                write!(f, "polonius-location-insensitive")
+            }
+        }
+    }
+}
+
+/// What the driver does with the crate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AnalysisMode {
+    /// Inject the given tests and report the verdicts of the selected engine
+    Tests,
+    /// Run each given test on its own under both NLL and Polonius, and report disagreements
+    Differential,
+    /// Test every place reachable from the user variables at every location, and report a
+    /// table of their capabilities
+    Capabilities,
+    /// Search forward from each given test's location for where it first succeeds
+    Search,
+    /// Inject the battery of tests before every statement, on every initialised user variable
+    Instrument,
+}
+
+/// What becomes of tests which use a place uninitialised on some path to them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UninitialisedTests {
+    /// Run them anyway, to see borrowck's own error
+    Run,
+    /// Report them as uninitialised without running them
+    Report,
+}
+
+impl FromStr for UninitialisedTests {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "run" => Ok(UninitialisedTests::Run),
+            "report" => Ok(UninitialisedTests::Report),
+            _ => Err(format!("unknown treatment of uninitialised tests {s:?}")),
+        }
+    }
+}
+
+impl fmt::Display for UninitialisedTests {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UninitialisedTests::Run => write!(f, "run"),
+            UninitialisedTests::Report => write!(f, "report"),
+        }
+    }
+}
+
+impl FromStr for AnalysisMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tests" => Ok(AnalysisMode::Tests),
+            "differential" => Ok(AnalysisMode::Differential),
+            "capabilities" => Ok(AnalysisMode::Capabilities),
+            "search" => Ok(AnalysisMode::Search),
+            "instrument" => Ok(AnalysisMode::Instrument),
+            _ => Err(format!("unknown analysis mode {s:?}")),
+        }
+    }
+}
+
+impl FromStr for ForgedUnwind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unreachable" => Ok(ForgedUnwind::Unreachable),
+            "terminate" => Ok(ForgedUnwind::Terminate),
+            "continue" => Ok(ForgedUnwind::Continue),
+            "cleanup" => Ok(ForgedUnwind::Cleanup),
+            _ => Err(format!("unknown unwind behaviour {s:?}")),
+        }
+    }
+}
+
+impl fmt::Display for ForgedUnwind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForgedUnwind::Unreachable => write!(f, "unreachable"),
+            ForgedUnwind::Terminate => write!(f, "terminate"),
+            ForgedUnwind::Continue => write!(f, "continue"),
+            ForgedUnwind::Cleanup => write!(f, "cleanup"),
+        }
+    }
+}
+
+impl FromStr for TestKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "move-out" => Ok(TestKind::MoveOut),
+            "move-in" => Ok(TestKind::MoveIn),
+            "shared-borrow" => Ok(TestKind::SharedBorrow),
+            "shallow-borrow" => Ok(TestKind::ShallowBorrow),
+            "mut-borrow" => Ok(TestKind::MutBorrow),
+            "two-phase-borrow" => Ok(TestKind::TwoPhaseBorrow),
+            "closure-capture-borrow" => Ok(TestKind::ClosureCaptureBorrow),
+            "raw-const" => Ok(TestKind::RawConst),
+            "raw-mut" => Ok(TestKind::RawMut),
+            "outlives" => Ok(TestKind::Outlives),
+            "write" => Ok(TestKind::Write),
+            "read" => Ok(TestKind::Read),
+            "storage-dead" => Ok(TestKind::StorageDead),
+            "drop" => Ok(TestKind::Drop),
+            "two-phase-activation" => Ok(TestKind::TwoPhaseActivation),
+            _ => Err(format!("unknown test kind {s:?}")),
+        }
+    }
+}
+
+impl fmt::Display for TestKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestKind::MoveOut => write!(f, "move-out"),
+            TestKind::MoveIn => write!(f, "move-in"),
+            TestKind::SharedBorrow => write!(f, "shared-borrow"),
+            TestKind::ShallowBorrow => write!(f, "shallow-borrow"),
+            TestKind::MutBorrow => write!(f, "mut-borrow"),
+            TestKind::TwoPhaseBorrow => write!(f, "two-phase-borrow"),
+            TestKind::ClosureCaptureBorrow => write!(f, "closure-capture-borrow"),
+            TestKind::RawConst => write!(f, "raw-const"),
+            TestKind::RawMut => write!(f, "raw-mut"),
+            TestKind::Outlives => write!(f, "outlives"),
+            TestKind::Write => write!(f, "write"),
+            TestKind::Read => write!(f, "read"),
+            TestKind::StorageDead => write!(f, "storage-dead"),
+            TestKind::Drop => write!(f, "drop"),
+            TestKind::TwoPhaseActivation => write!(f, "two-phase-activation"),
+        }
+    }
+}
+
+/// One projection in a place given on the command line
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum PlaceElemSpec {
+    Deref,
+    Field(FieldIdx),
+}
+
+/// A place given on the command line: `_N` followed by `.K` for fields and `.*` for derefs,
+/// so `_5.0.*` is `(*(_5.0))`
+#[derive(Clone, Debug)]
+pub(crate) struct PlaceSpec {
+    pub local: Local,
+    pub projection: Vec<PlaceElemSpec>,
+}
+
+impl PlaceSpec {
+    /// The place in a body with the given locals, or why it has no such place
+    pub fn to_place<'tcx>(
+        &self,
+        tcx: TyCtxt<'tcx>,
+        local_decls: &LocalDecls<'tcx>,
+    ) -> Result<Place<'tcx>, String> {
+        if local_decls.get(self.local).is_none() {
+            return Err(format!("{}: the body has no local {:?}", self, self.local));
+        }
+        let mut place = Place::from(self.local);
+        for elem in self.projection.iter() {
+            let ty = place.ty(local_decls, tcx).ty;
+            place = match elem {
+                PlaceElemSpec::Deref => {
+                    if ty.builtin_deref(true).is_none() {
+                        return Err(format!("{}: {:?} of type {} is not a pointer", self, place, ty));
+                    }
+                    tcx.mk_place_deref(place)
+                }
+                PlaceElemSpec::Field(field) => {
+                    // Places are given without downcasts, so only structs, unions and tuples
+                    //  have fields to project to
+                    let field_ty = match ty.kind() {
+                        ty::Adt(adt, args) if !adt.is_enum() => adt
+                            .non_enum_variant()
+                            .fields
+                            .get(*field)
+                            .map(|field| field.ty(tcx, args)),
+                        ty::Tuple(tys) => tys.get(field.index()).copied(),
+                        _ => None,
+                    };
+                    let Some(field_ty) = field_ty else {
+                        return Err(format!(
+                            "{}: {:?} of type {} has no field {}",
+                            self,
+                            place,
+                            ty,
+                            field.index()
+                        ));
+                    };
+                    tcx.mk_place_field(place, *field, field_ty)
+                }
+            };
+        }
+        Ok(place)
+    }
+}
+
+impl FromStr for PlaceSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('.');
+        let local = parts
+            .next()
+            .and_then(|base| base.strip_prefix('_'))
+            .and_then(|index| index.parse::<u32>().ok())
+            .ok_or_else(|| format!("place {s:?} does not start with a local"))?;
+        let projection = parts
+            .map(|part| match part {
+                "*" => Ok(PlaceElemSpec::Deref),
+                _ => part
+                    .parse::<u32>()
+                    .map(|field| PlaceElemSpec::Field(FieldIdx::from_u32(field)))
+                    .map_err(|_| format!("bad projection {part:?} in place {s:?}")),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(PlaceSpec {
+            local: Local::from_u32(local),
+            projection,
+        })
+    }
+}
+
+impl fmt::Display for PlaceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "_{}", self.local.as_u32())?;
+        for elem in self.projection.iter() {
+            match elem {
+                PlaceElemSpec::Deref => write!(f, ".*")?,
+                PlaceElemSpec::Field(field) => write!(f, ".{}", field.as_u32())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a location in MIR dump syntax, `bb<block>[<statement>]`
+pub(crate) fn parse_location(s: &str) -> Result<Location, String> {
+    let (block, statement_index) = s
+        .strip_prefix("bb")
+        .and_then(|rest| rest.strip_suffix(']'))
+        .and_then(|rest| rest.split_once('['))
+        .ok_or_else(|| format!("location {s:?} is not of the form bb<N>[<M>]"))?;
+    let block = block
+        .parse::<u32>()
+        .map_err(|_| format!("bad block in location {s:?}"))?;
+    let statement_index = statement_index
+        .parse::<usize>()
+        .map_err(|_| format!("bad statement index in location {s:?}"))?;
+    Ok(Location {
+        block: BasicBlock::from_u32(block),
+        statement_index,
+    })
+}
+
+/// A test to inject, given as `<function>@<location>:<place>:<kind>`,
+/// e.g. `main@bb0[5]:_1:move-out`. Locations refer to the unmodified MIR.
+/// Two-phase activation tests take a range of locations, `bb0[2]..bb1[0]`.
+#[derive(Clone, Debug)]
+pub(crate) struct TestSpec {
+    /// Def path of the function, as printed by `TyCtxt::def_path_str`
+    pub function: String,
+    pub location: Location,
+    pub place: PlaceSpec,
+    pub kind: TestKind,
+    /// Reference-typed place whose region the test must outlive; required by outlives tests
+    pub outlives: Option<PlaceSpec>,
+    /// Where a two-phase activation test activates the borrow it reserves at location
+    pub activation: Option<Location>,
+}
+
+impl FromStr for TestSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (function, rest) = s
+            .rsplit_once('@')
+            .ok_or_else(|| format!("test {s:?} does not name a function"))?;
+        let (location, place, kind, outlives) = match rest.split(':').collect::<Vec<_>>()[..] {
+            [location, place, kind] => (location, place, kind, None),
+            [location, place, kind, outlives] => (location, place, kind, Some(outlives)),
+            _ => {
+                return Err(format!(
+                    "test {s:?} is not of the form <function>@<location>:<place>:<kind>[:<place>]"
+                ))
+            }
+        };
+        let kind: TestKind = kind.parse()?;
+        match (kind, outlives) {
+            (TestKind::Outlives, None) => {
+                return Err(format!("test {s:?} does not name the place to outlive"))
+            }
+            (
+                TestKind::MoveOut
+                | TestKind::MoveIn
+                | TestKind::Write
+                | TestKind::Read
+                | TestKind::StorageDead
+                | TestKind::Drop
+                | TestKind::TwoPhaseActivation,
+                Some(_),
+            ) => {
+                return Err(format!(
+                    "test {s:?}: only borrow and outlives tests take a second place"
+                ))
+            }
+            (TestKind::RawConst | TestKind::RawMut, Some(_)) => {
+                return Err(format!("test {s:?}: raw pointers have no region to tie"))
+            }
+            _ => {}
+        }
+        let (location, activation) = match location.split_once("..") {
+            Some((reservation, activation)) => (reservation, Some(parse_location(activation)?)),
+            None => (location, None),
+        };
+        if (kind == TestKind::TwoPhaseActivation) != activation.is_some() {
+            return Err(format!(
+                "test {s:?}: two-phase activation tests, and only they, take a location range"
+            ));
+        }
+        let place: PlaceSpec = place.parse()?;
+        if kind == TestKind::StorageDead && !place.projection.is_empty() {
+            return Err(format!("test {s:?}: only a local's storage can be ended"));
+        }
+        Ok(TestSpec {
+            function: function.to_owned(),
+            location: parse_location(location)?,
+            place,
+            kind,
+            outlives: outlives.map(str::parse).transpose()?,
+            activation,
+        })
+    }
+}
+
+impl fmt::Display for TestSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Location's Debug output is already bb<N>[<M>]
+        write!(f, "{}@{:?}", self.function, self.location)?;
+        if let Some(activation) = &self.activation {
+            write!(f, "..{:?}", activation)?;
+        }
+        write!(f, ":{}:{}", self.place, self.kind)?;
+        if let Some(outlives) = &self.outlives {
+            write!(f, ":{}", outlives)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tests to inject wherever a local is live, given as `<function>@live(<local>):<place>:<kind>`,
+/// e.g. `main@live(_3):_1:mut-borrow`. Liveness is that of the unmodified MIR.
+#[derive(Clone, Debug)]
+pub(crate) struct LivePlan {
+    pub local: Local,
+    /// The test to inject, whose location is replaced by each location where local is live
+    pub test: TestSpec,
+}
+
+/// Parses a live plan, if the location of the test is `live(<local>)`
+fn parse_live_plan(s: &str) -> Option<Result<LivePlan, String>> {
+    let (function, rest) = s.split_once('@')?;
+    let (local, rest) = rest.strip_prefix("live(")?.split_once("):")?;
+    let parse = || {
+        let local: PlaceSpec = local.parse()?;
+        if !local.projection.is_empty() {
+            return Err(format!("plan {s:?}: liveness is only known for locals"));
+        }
+        // The location is replaced when the plan is expanded
+        let test: TestSpec = format!("{function}@bb0[0]:{rest}").parse()?;
+        if test.kind == TestKind::TwoPhaseActivation {
+            return Err(format!(
+                "plan {s:?}: two-phase activation tests take a location range"
+            ));
+        }
+        Ok(LivePlan {
+            local: local.local,
+            test,
+        })
+    };
+    Some(parse())
+}
+
+impl fmt::Display for LivePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}@live({:?}):{}:{}",
+            self.test.function, self.local, self.test.place, self.test.kind
+        )?;
+        if let Some(outlives) = &self.test.outlives {
+            write!(f, ":{}", outlives)?;
+        }
+        Ok(())
+    }
+}
+
+/// Code an approximator runs on the extra path it adds
+#[derive(Clone, Debug)]
+pub(crate) enum ApproximatorTemplate {
+    /// `havoc:<place>,...`: overwrite each place with a fresh value
+    Havoc(Vec<PlaceSpec>),
+    /// `forget:<place>,...`: move each reference into a sink
+    Forget(Vec<PlaceSpec>),
+    /// `expire-loan:<location>`: the loan taken at the location has expired
+    ExpiredLoan(Location),
+}
+
+/// An approximator to insert, given as `<function>@<location>:<template>`,
+/// e.g. `main@bb1[0]:havoc:_1,_2.0`. Locations refer to the unmodified MIR.
+#[derive(Clone, Debug)]
+pub(crate) struct ApproximatorSpec {
+    pub function: String,
+    pub location: Location,
+    pub template: ApproximatorTemplate,
+}
+
+impl FromStr for ApproximatorSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = || {
+            let (function, rest) = s.split_once('@')?;
+            let (location, rest) = rest.split_once(':')?;
+            let (template, argument) = rest.split_once(':')?;
+            Some((function, location, template, argument))
+        };
+        let (function, location, template, argument) = parse().ok_or_else(|| {
+            format!("approximator {s:?} is not of the form <function>@<location>:<template>")
+        })?;
+        let places = || argument.split(',').map(str::parse).collect::<Result<_, _>>();
+        let template = match template {
+            "havoc" => ApproximatorTemplate::Havoc(places()?),
+            "forget" => ApproximatorTemplate::Forget(places()?),
+            "expire-loan" => ApproximatorTemplate::ExpiredLoan(parse_location(argument)?),
+            _ => return Err(format!("unknown approximator template {template:?}")),
+        };
+        Ok(ApproximatorSpec {
+            function: function.to_owned(),
+            location: parse_location(location)?,
+            template,
+        })
+    }
+}
+
+impl fmt::Display for ApproximatorSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{:?}:", self.function, self.location)?;
+        let places = |places: &[PlaceSpec]| {
+            let places: Vec<_> = places.iter().map(PlaceSpec::to_string).collect();
+            places.join(",")
+        };
+        match &self.template {
+            ApproximatorTemplate::Havoc(to_havoc) => write!(f, "havoc:{}", places(to_havoc)),
+            ApproximatorTemplate::Forget(to_forget) => write!(f, "forget:{}", places(to_forget)),
+            ApproximatorTemplate::ExpiredLoan(location) => write!(f, "expire-loan:{:?}", location),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AnalysisConfig {
+    /// `--analysis-mode=tests|differential|capabilities|search|instrument`
+    pub mode: AnalysisMode,
+
+    /// `--analysis-engine=nll|polonius|polonius-location-insensitive`
+    pub engine: BorrowckEngine,
+
+    /// `--analysis-report=<path>`: where to write the JSON report. If the path is a
+    /// directory, each crate's report is written to `<path>/<crate>.json`.
+    pub report: Option<PathBuf>,
+
+    /// `--analysis-test=<spec>`, may be repeated
+    pub tests: Vec<TestSpec>,
+
+    /// `--analysis-test=<plan>` with a `live(<local>)` location, may be repeated
+    pub live_plans: Vec<LivePlan>,
+
+    /// `--analysis-facts-dir=<dir>`: where to export the Polonius facts of rewritten bodies
+    pub facts_dir: Option<PathBuf>,
+
+    /// `--analysis-unwind=unreachable|terminate|continue|cleanup`: how forged calls unwind
+    pub unwind: ForgedUnwind,
+
+    /// `--analysis-reborrow-graph=<dir>`: where to write the reborrow graph of every body
+    pub reborrow_graph_dir: Option<PathBuf>,
+
+    /// `--analysis-battery=<kind>,...`: the tests instrument mode injects before each statement
+    pub battery: Vec<TestKind>,
+
+    /// `--analysis-uninitialised=run|report`: what to do with tests of uninitialised places
+    pub uninitialised: UninitialisedTests,
+
+    /// `--analysis-approximate=<spec>`, may be repeated
+    pub approximators: Vec<ApproximatorSpec>,
+
+    /// `--analysis-dump-mir=<dir>`: where rustc dumps the MIR of every pass, if anywhere
+    pub dump_mir_dir: Option<PathBuf>,
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        AnalysisConfig {
+            mode: AnalysisMode::Tests,
+            engine: BorrowckEngine::Polonius,
+            report: None,
+            tests: vec![],
+            live_plans: vec![],
+            facts_dir: None,
+            unwind: ForgedUnwind::Terminate,
+            reborrow_graph_dir: None,
+            battery: vec![TestKind::MoveOut, TestKind::MutBorrow, TestKind::SharedBorrow],
+            uninitialised: UninitialisedTests::Report,
+            approximators: vec![],
+            dump_mir_dir: None,
+        }
+    }
+}
+
+impl AnalysisConfig {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut config = AnalysisConfig::default();
+        for arg in args.iter() {
+            let (key, value) = arg
+                .strip_prefix("--analysis-")
+                .and_then(|option| option.split_once('='))
+                .ok_or_else(|| format!("malformed option {arg:?}"))?;
+            match key {
+                "mode" => config.mode = value.parse()?,
+                "engine" => config.engine = value.parse()?,
+                "report" => config.report = Some(PathBuf::from(value)),
+                "test" => match parse_live_plan(value) {
+                    Some(plan) => config.live_plans.push(plan?),
+                    None => config.tests.push(value.parse()?),
+                },
+                "facts-dir" => config.facts_dir = Some(PathBuf::from(value)),
+                "unwind" => config.unwind = value.parse()?,
+                "reborrow-graph" => config.reborrow_graph_dir = Some(PathBuf::from(value)),
+                "battery" => config.battery = parse_battery(value)?,
+                "uninitialised" => config.uninitialised = value.parse()?,
+                "approximate" => config.approximators.push(value.parse()?),
+                "dump-mir" => config.dump_mir_dir = Some(PathBuf::from(value)),
+                _ => return Err(format!("unknown option {arg:?}")),
+            }
+        }
+        // A search starts from one location, which a live plan does not give
+        if config.mode == AnalysisMode::Search {
+            if let Some(plan) = config.live_plans.first() {
+                return Err(format!("plan {plan} has no location to search from"));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Options shaping how bodies are rewritten, as arguments for a child compilation: all
+    /// but the mode, the engine, the tests and where the outputs go, which the parent chooses
+    pub fn rewrite_args(&self) -> Vec<String> {
+        let mut args = vec![
+            format!("--analysis-unwind={}", self.unwind),
+            format!("--analysis-uninitialised={}", self.uninitialised),
+        ];
+        args.extend(
+            self.approximators
+                .iter()
+                .map(|approximator| format!("--analysis-approximate={}", approximator)),
+        );
+        args
+    }
+
+    /// Tests to inject into the function with the given def path
+    pub fn tests_for<'a>(&'a self, function: &'a str) -> impl Iterator<Item = &'a TestSpec> {
+        self.tests
+            .iter()
+            .filter(move |spec| spec.function == function)
+    }
+
+    /// Approximators to insert into the function with the given def path
+    pub fn approximators_for<'a>(
+        &'a self,
+        function: &'a str,
+    ) -> impl Iterator<Item = &'a ApproximatorSpec> {
+        self.approximators
+            .iter()
+            .filter(move |spec| spec.function == function)
+    }
+
+    /// Plans for tests wherever a local of the function with the given def path is live
+    pub fn live_plans_for<'a>(&'a self, function: &'a str) -> impl Iterator<Item = &'a LivePlan> {
+        self.live_plans
+            .iter()
+            .filter(move |plan| plan.test.function == function)
+    }
+}
+
+/// Parses a comma-separated list of test kinds which need nothing but a place
+fn parse_battery(s: &str) -> Result<Vec<TestKind>, String> {
+    s.split(',')
+        .map(|kind| {
+            let kind: TestKind = kind.parse()?;
+            match kind {
+                TestKind::Outlives | TestKind::TwoPhaseActivation => Err(format!(
+                    "{kind} tests need more than a place, so cannot be in a battery"
+                )),
+                _ => Ok(kind),
+            }
+        })
+        .collect()
+}
+
+static CONFIG: OnceLock<AnalysisConfig> = OnceLock::new();
+
+pub(crate) fn init(config: AnalysisConfig) {
+    CONFIG
+        .set(config)
+        .expect("analysis options were already set");
+}
+
+pub(crate) fn config() -> &'static AnalysisConfig {
+    CONFIG.get().expect("analysis options are not set")
+}
@@ -0,0 +1,195 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Differential mode: every test is compiled on its own under NLL and under Polonius,
+//! and every test on which the two disagree is reported with both engines' diagnostics.
+//!
+//! Running the tests separately means a body-level rejection is always the test's own.
+//! A live plan is first expanded by a child compilation, then each of its tests is run alone.
+
+use crate::config::{self, BorrowckEngine, TestSpec};
+use crate::report::{TestReport, Verdict};
+use crate::runner::{self, ChildOutcome};
+use serde::Serialize;
+use std::fs;
+
+#[derive(Debug, Serialize)]
+struct EngineResult {
+    verdict: Verdict,
+    diagnostics: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Disagreement {
+    test: String,
+    nll: EngineResult,
+    polonius: EngineResult,
+}
+
+#[derive(Debug, Serialize)]
+struct DifferentialReport {
+    tests_run: usize,
+    disagreements: Vec<Disagreement>,
+    /// Tests and plans for which a child compilation reported nothing
+    failures: Vec<String>,
+}
+
+/// Compiles the crate with a test, or a live plan, under the given engine
+///
+/// A child which reports no test has failed, e.g. by crashing; that is an error rather
+/// than a verdict.
+fn run_under(
+    rustc_args: &[String],
+    test: &str,
+    engine: BorrowckEngine,
+) -> Result<(Vec<TestReport>, Vec<String>), String> {
+    let ChildOutcome {
+        report,
+        diagnostics,
+    } = runner::run_child(
+        rustc_args,
+        &[
+            format!("--analysis-engine={}", engine),
+            format!("--analysis-test={}", test),
+        ],
+    );
+    let tests: Vec<_> = report
+        .into_iter()
+        .flat_map(|report| report.functions)
+        .flat_map(|function| function.tests)
+        .collect();
+    if tests.is_empty() {
+        let mut error = format!("{} under {:?} reported no test", test, engine);
+        for diagnostic in diagnostics.iter() {
+            error.push('\n');
+            error.push_str(diagnostic);
+        }
+        return Err(error);
+    }
+    Ok((tests, diagnostics))
+}
+
+/// Runs a single test under the given engine
+fn run_test_under(
+    rustc_args: &[String],
+    spec: &TestSpec,
+    engine: BorrowckEngine,
+) -> Result<EngineResult, String> {
+    let (tests, diagnostics) = run_under(rustc_args, &spec.to_string(), engine)?;
+    Ok(EngineResult {
+        verdict: tests[0].verdict,
+        diagnostics,
+    })
+}
+
+/// Lays out two blocks of text in columns
+fn side_by_side(left: &str, right: &str, width: usize) -> String {
+    let left_lines: Vec<_> = left.lines().collect();
+    let right_lines: Vec<_> = right.lines().collect();
+    (0..left_lines.len().max(right_lines.len()))
+        .map(|i| {
+            format!(
+                "{:width$} | {}\n",
+                left_lines.get(i).unwrap_or(&""),
+                right_lines.get(i).unwrap_or(&""),
+                width = width
+            )
+        })
+        .collect()
+}
+
+fn print_disagreement(disagreement: &Disagreement) {
+    const WIDTH: usize = 80;
+    println!("[warn] engines disagree on {}", disagreement.test);
+    println!(
+        "{}",
+        side_by_side(
+            &format!("NLL: {:?}", disagreement.nll.verdict),
+            &format!("Polonius: {:?}", disagreement.polonius.verdict),
+            WIDTH
+        )
+    );
+    println!(
+        "{}",
+        side_by_side(
+            &disagreement.nll.diagnostics.join("\n"),
+            &disagreement.polonius.diagnostics.join("\n"),
+            WIDTH
+        )
+    );
+}
+
+/// Runs every configured test, and every test of the configured live plans, under both engines
+pub(crate) fn run(rustc_args: &[String]) {
+    let mut specs = config::config().tests.clone();
+    let mut failures = vec![];
+    // Where a plan places its tests is only known once the child has built the body
+    for plan in config::config().live_plans.iter() {
+        match run_under(rustc_args, &plan.to_string(), BorrowckEngine::Polonius) {
+            Ok((tests, _)) => specs.extend(
+                tests
+                    .iter()
+                    .filter(|test| test.verdict != Verdict::Uninitialised)
+                    .filter_map(|test| config::parse_location(&test.location).ok())
+                    .map(|location| TestSpec {
+                        location,
+                        ..plan.test.clone()
+                    }),
+            ),
+            Err(error) => {
+                println!("[error] {}", error);
+                failures.push(plan.to_string());
+            }
+        }
+    }
+
+    let mut disagreements = vec![];
+    for spec in specs.iter() {
+        let results = run_test_under(rustc_args, spec, BorrowckEngine::Nll).and_then(|nll| {
+            Ok((nll, run_test_under(rustc_args, spec, BorrowckEngine::Polonius)?))
+        });
+        let (nll, polonius) = match results {
+            Ok(results) => results,
+            Err(error) => {
+                println!("[error] {}", error);
+                failures.push(spec.to_string());
+                continue;
+            }
+        };
+        if nll.verdict != polonius.verdict {
+            let disagreement = Disagreement {
+                test: spec.to_string(),
+                nll,
+                polonius,
+            };
+            print_disagreement(&disagreement);
+            disagreements.push(disagreement);
+        }
+    }
+
+    println!(
+        "[info] {} of {} tests differ between NLL and Polonius, {} could not be run",
+        disagreements.len(),
+        specs.len(),
+        failures.len()
+    );
+
+    let report = DifferentialReport {
+        tests_run: specs.len(),
+        disagreements,
+        failures,
+    };
+    if let Some(path) = &config::config().report {
+        let path = if path.is_dir() {
+            path.join("differential.json")
+        } else {
+            path.to_owned()
+        };
+        let file = fs::File::create(&path).expect("could not create the report file");
+        serde_json::to_writer_pretty(file, &report).expect("could not write the report");
+        println!("[info] report written to {}", path.display());
+    }
+}
@@ -0,0 +1,251 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Explanations of rejected tests: which loans block a test, where they were taken, and
+//! which live references keep them alive.
+//!
+//! Blocking loans are those of the Polonius errors at the test's own code. Borrowck checks a
+//! body whose blocks have been merged and renumbered since the rewrite, so every block of the
+//! unmodified MIR is marked by ghost code at its start, ending the storage of a ghost local of
+//! its own. Its statements are found again in the body borrowck checks after its marker, on
+//! the real path, which is how loans are traced back to the borrows of the unmodified MIR.
+
+use crate::cleanup::ForgedItems;
+use crate::reborrows::LoanHolders;
+use crate::BodyModifier;
+use rustc_borrowck::consumers::{BodyWithBorrowckFacts, RichLocation};
+use rustc_hash::{FxHashMap, FxHashSet};
+use rustc_middle::mir::{
+    BasicBlock, Body, Local, Location, Statement, StatementKind, TerminatorKind,
+};
+use rustc_middle::ty::TyCtxt;
+use rustc_span::def_id::LocalDefId;
+use rustc_span::Span;
+use serde::{Deserialize, Serialize};
+use std::sync::{LazyLock, Mutex};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct BlockingLoan {
+    pub loan: String,
+    /// The place the loan borrows
+    pub borrowed: String,
+    /// Where the loan was taken in the unmodified MIR; None for loans taken by forged code,
+    /// or by a borrow in a cleanup block, which is not marked
+    pub created_at: Option<String>,
+    pub span: String,
+    /// Live locals holding the loan where it blocks the test
+    pub held_by: Vec<String>,
+}
+
+/// A block of the unmodified MIR, as its ghost marker knows it
+#[derive(Debug)]
+pub(crate) struct OriginalBlock {
+    block: BasicBlock,
+    /// Span and kind of each statement, see statement_key
+    statements: Vec<(Span, String)>,
+}
+
+/// The ghost local marking the start of each block of the unmodified MIR
+pub(crate) type BlockMarkers = FxHashMap<Local, OriginalBlock>;
+
+/// Block markers of every rewritten body, waiting for borrowck
+static BLOCK_MARKERS: LazyLock<Mutex<FxHashMap<LocalDefId, BlockMarkers>>> =
+    LazyLock::new(Default::default);
+
+pub(crate) fn register_block_markers(def_id: LocalDefId, markers: BlockMarkers) {
+    BLOCK_MARKERS.lock().unwrap().insert(def_id, markers);
+}
+
+pub(crate) fn take_block_markers(def_id: LocalDefId) -> Option<BlockMarkers> {
+    BLOCK_MARKERS.lock().unwrap().remove(&def_id)
+}
+
+/// What identifies a statement among those of its block, through the passes before borrowck
+///
+/// Promotion rewrites the borrows it promotes and removes the statements of the promoted
+/// temporaries, so assignments are only known by the place they assign.
+fn statement_key(statement: &Statement<'_>) -> (Span, String) {
+    let kind = match &statement.kind {
+        StatementKind::Assign(box (place, _)) => format!("{:?} = ..", place),
+        kind => format!("{:?}", kind),
+    };
+    (statement.source_info.span, kind)
+}
+
+/// Marks the start of every block of the unmodified body, once its tests and approximators
+/// are inserted
+///
+/// Cleanup blocks are left unmarked, as ghost code cannot be inserted there.
+pub(crate) fn mark_original_blocks<'tcx>(
+    body_modifier: &mut BodyModifier<'_, 'tcx>,
+    body: &Body<'tcx>,
+) -> BlockMarkers {
+    let mut markers = BlockMarkers::default();
+    for (block, data) in body.basic_blocks.iter_enumerated() {
+        if data.is_cleanup {
+            continue;
+        }
+        let marker = body_modifier.allocate_ghost_local(body_modifier.tcx.types.unit);
+        body_modifier.insert_ghost_statements(
+            &block.start_location(),
+            vec![StatementKind::StorageDead(marker)],
+        );
+        markers.insert(
+            marker,
+            OriginalBlock {
+                block,
+                statements: data.statements.iter().map(statement_key).collect(),
+            },
+        );
+    }
+    markers
+}
+
+/// Locates the statements and terminator of an original block, starting just after its
+/// marker and following the real path through the splits forged code made in the block
+fn locate_block(
+    body: &Body<'_>,
+    forged: &ForgedItems,
+    markers: &BlockMarkers,
+    original: &OriginalBlock,
+    mut location: Location,
+    located: &mut FxHashMap<Location, Location>,
+) {
+    let mut next_index = 0;
+    let mut visited = FxHashSet::default();
+    loop {
+        let data = &body.basic_blocks[location.block];
+        match data.statements.get(location.statement_index) {
+            // SimplifyCfg merges blocks, so the block may go on with the next original one
+            Some(Statement {
+                kind: StatementKind::StorageDead(local),
+                ..
+            }) if markers.contains_key(local) => return,
+            Some(statement) => {
+                if !forged.is_mentioned_in_statement(statement, location) {
+                    // Skips the statements promotion removed
+                    let key = statement_key(statement);
+                    let Some(offset) = original.statements[next_index..]
+                        .iter()
+                        .position(|original_key| *original_key == key)
+                    else {
+                        return;
+                    };
+                    let statement_index = next_index + offset;
+                    located.insert(
+                        location,
+                        Location {
+                            block: original.block,
+                            statement_index,
+                        },
+                    );
+                    next_index = statement_index + 1;
+                }
+                location.statement_index += 1;
+            }
+            None => match data.terminator().kind {
+                // Test and approximator splits are the only FalseEdges into forged blocks;
+                //  SimplifyCfg merged those of ghost code away
+                TerminatorKind::FalseEdge {
+                    real_target,
+                    imaginary_target,
+                } if forged.is_forged_block(body, imaginary_target) => {
+                    if !visited.insert(real_target) {
+                        return;
+                    }
+                    location = real_target.start_location();
+                }
+                _ => {
+                    located.insert(
+                        location,
+                        Location {
+                            block: original.block,
+                            statement_index: original.statements.len(),
+                        },
+                    );
+                    return;
+                }
+            },
+        }
+    }
+}
+
+/// Locations of the body borrowck checks, mapped to their locations in the unmodified MIR
+///
+/// forged holds everything forged in the body, the markers included. Forged code has no
+/// original location, nor does code in cleanup blocks.
+pub(crate) fn locate_original_locations(
+    body: &Body<'_>,
+    forged: &ForgedItems,
+    markers: &BlockMarkers,
+) -> FxHashMap<Location, Location> {
+    let mut located = FxHashMap::default();
+    for (block, data) in body.basic_blocks.iter_enumerated() {
+        for (statement_index, statement) in data.statements.iter().enumerate() {
+            let StatementKind::StorageDead(marker) = statement.kind else {
+                continue;
+            };
+            let Some(original) = markers.get(&marker) else {
+                continue;
+            };
+            let after_marker = Location {
+                block,
+                statement_index: statement_index + 1,
+            };
+            locate_block(body, forged, markers, original, after_marker, &mut located);
+        }
+    }
+    located
+}
+
+/// The loans blocking a test, from the Polonius errors at its code
+pub(crate) fn blocking_loans<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    facts: &BodyWithBorrowckFacts<'tcx>,
+    holders: &LoanHolders,
+    forged: &ForgedItems,
+    original_locations: &FxHashMap<Location, Location>,
+) -> Vec<BlockingLoan> {
+    let Some(location_table) = &facts.location_table else {
+        return vec![];
+    };
+    let mut blocking: Vec<BlockingLoan> = vec![];
+    for (point, loans) in holders.output.errors.iter() {
+        let (RichLocation::Start(location) | RichLocation::Mid(location)) =
+            location_table.to_location(*point);
+        if !forged.owns(&facts.body, location) {
+            continue;
+        }
+        let held = holders.at(*point);
+        for loan in loans.iter() {
+            let loan_name = format!("{:?}", loan);
+            if blocking.iter().any(|known| known.loan == loan_name) {
+                continue;
+            }
+            let borrow = &facts.borrow_set[*loan];
+            let created_at = if forged.owns(&facts.body, borrow.reserve_location) {
+                None
+            } else {
+                original_locations
+                    .get(&borrow.reserve_location)
+                    .map(|location| format!("{:?}", location))
+            };
+            let span = facts.body.source_info(borrow.reserve_location).span;
+            blocking.push(BlockingLoan {
+                loan: loan_name,
+                borrowed: format!("{:?}", borrow.borrowed_place),
+                created_at,
+                span: tcx.sess.source_map().span_to_embeddable_string(span),
+                held_by: held
+                    .iter()
+                    .filter(|(held_loan, _)| held_loan == loan)
+                    .map(|(_, local)| holders.local_name(*local))
+                    .collect(),
+            });
+        }
+    }
+    blocking
+}
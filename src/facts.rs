@@ -0,0 +1,299 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Export of the Polonius facts for a rewritten body.
+//!
+//! Relations are written one per file, tab separated, in the style of `-Znll-facts`, but
+//! with points shown as locations. Forged points, loans and origins are listed in the extra
+//! relations `forged_point`, `forged_loan` and `forged_origin`, the latter with the
+//! crate-wide id of each forged origin.
+//!
+//! The error relations are those borrowck computed. rustc keeps none of the intermediate
+//! ones, so those are computed again with the naive algorithm.
+
+use crate::cleanup::ForgedItems;
+use crate::forged_ids::{self, ForgedRegionId};
+use polonius_engine::{Algorithm, FactTypes, Output};
+use rustc_borrowck::consumers::{BodyWithBorrowckFacts, BorrowIndex, RichLocation, RustcFacts};
+use rustc_hash::{FxHashMap, FxHashSet};
+use rustc_span::def_id::LocalDefId;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+type Point = <RustcFacts as FactTypes>::Point;
+type Loan = <RustcFacts as FactTypes>::Loan;
+type Origin = <RustcFacts as FactTypes>::Origin;
+
+/// The atoms which only exist because of forged code
+#[derive(Debug, Default)]
+struct ForgedAtoms {
+    points: FxHashSet<Point>,
+    loans: FxHashSet<Loan>,
+    origins: FxHashMap<Origin, ForgedRegionId>,
+}
+
+impl ForgedAtoms {
+    fn new(def_id: LocalDefId, facts: &BodyWithBorrowckFacts<'_>, forged: &ForgedItems) -> Self {
+        let location_table = facts.location_table.as_ref().unwrap();
+        let mut atoms = ForgedAtoms::default();
+
+        // Ghost code merged into real blocks only makes its own points forged
+        for point in location_table.all_points() {
+            let (RichLocation::Start(location) | RichLocation::Mid(location)) =
+                location_table.to_location(point);
+            if forged.owns(&facts.body, location) {
+                atoms.points.insert(point);
+            }
+        }
+
+        for (index, borrow) in facts.borrow_set.location_map.values().enumerate() {
+            if forged.is_mentioned_at(&facts.body, borrow.reserve_location) {
+                atoms.loans.insert(BorrowIndex::from_usize(index));
+            }
+        }
+        atoms.origins = forged_ids::forged_regions(def_id);
+        atoms
+    }
+}
+
+struct FactWriter<'a, 'tcx> {
+    dir: PathBuf,
+    facts: &'a BodyWithBorrowckFacts<'tcx>,
+}
+
+impl<'a, 'tcx> FactWriter<'a, 'tcx> {
+    fn point(&self, point: &Point) -> String {
+        let location_table = self.facts.location_table.as_ref().unwrap();
+        format!("{:?}", location_table.to_location(*point))
+    }
+
+    fn atom(&self, atom: &impl std::fmt::Debug) -> String {
+        format!("{:?}", atom)
+    }
+
+    fn write(&self, relation: &str, rows: impl Iterator<Item = Vec<String>>) {
+        let path = self.dir.join(format!("{}.facts", relation));
+        let mut file = fs::File::create(&path).expect("could not create a facts file");
+        for row in rows {
+            writeln!(file, "{}", row.join("\t")).expect("could not write a facts file");
+        }
+    }
+}
+
+/// A function's def path, made safe to use as a file name
+pub(crate) fn function_file_name(function: &str) -> String {
+    function
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Writes the input facts, the output relations and the forged atoms of one body
+/// into `<dir>/<function>/`
+pub(crate) fn export(
+    def_id: LocalDefId,
+    dir: &Path,
+    function: &str,
+    facts: &BodyWithBorrowckFacts<'_>,
+    forged: &ForgedItems,
+) {
+    let dir = dir.join(function_file_name(function));
+    fs::create_dir_all(&dir).expect("could not create the facts directory");
+    let w = &FactWriter { dir, facts };
+
+    let input = facts.input_facts.as_ref().unwrap();
+    w.write(
+        "loan_issued_at",
+        input
+            .loan_issued_at
+            .iter()
+            .map(|(o, l, p)| vec![w.atom(o), w.atom(l), w.point(p)]),
+    );
+    w.write(
+        "universal_region",
+        input.universal_region.iter().map(|o| vec![w.atom(o)]),
+    );
+    w.write(
+        "cfg_edge",
+        input
+            .cfg_edge
+            .iter()
+            .map(|(p, q)| vec![w.point(p), w.point(q)]),
+    );
+    w.write(
+        "loan_killed_at",
+        input
+            .loan_killed_at
+            .iter()
+            .map(|(l, p)| vec![w.atom(l), w.point(p)]),
+    );
+    w.write(
+        "subset_base",
+        input
+            .subset_base
+            .iter()
+            .map(|(o1, o2, p)| vec![w.atom(o1), w.atom(o2), w.point(p)]),
+    );
+    w.write(
+        "loan_invalidated_at",
+        input
+            .loan_invalidated_at
+            .iter()
+            .map(|(p, l)| vec![w.point(p), w.atom(l)]),
+    );
+    for (relation, rows) in [
+        ("var_used_at", &input.var_used_at),
+        ("var_defined_at", &input.var_defined_at),
+        ("var_dropped_at", &input.var_dropped_at),
+    ] {
+        w.write(
+            relation,
+            rows.iter().map(|(v, p)| vec![w.atom(v), w.point(p)]),
+        );
+    }
+    for (relation, rows) in [
+        ("use_of_var_derefs_origin", &input.use_of_var_derefs_origin),
+        (
+            "drop_of_var_derefs_origin",
+            &input.drop_of_var_derefs_origin,
+        ),
+    ] {
+        w.write(
+            relation,
+            rows.iter().map(|(v, o)| vec![w.atom(v), w.atom(o)]),
+        );
+    }
+    w.write(
+        "child_path",
+        input
+            .child_path
+            .iter()
+            .map(|(c, p)| vec![w.atom(c), w.atom(p)]),
+    );
+    w.write(
+        "path_is_var",
+        input
+            .path_is_var
+            .iter()
+            .map(|(m, v)| vec![w.atom(m), w.atom(v)]),
+    );
+    for (relation, rows) in [
+        ("path_assigned_at_base", &input.path_assigned_at_base),
+        ("path_moved_at_base", &input.path_moved_at_base),
+        ("path_accessed_at_base", &input.path_accessed_at_base),
+    ] {
+        w.write(
+            relation,
+            rows.iter().map(|(m, p)| vec![w.atom(m), w.point(p)]),
+        );
+    }
+    w.write(
+        "known_placeholder_subset",
+        input
+            .known_placeholder_subset
+            .iter()
+            .map(|(o1, o2)| vec![w.atom(o1), w.atom(o2)]),
+    );
+    w.write(
+        "placeholder",
+        input
+            .placeholder
+            .iter()
+            .map(|(o, l)| vec![w.atom(o), w.atom(l)]),
+    );
+
+    // The errors are the ones borrowck computed, with the algorithm it was asked for
+    let errors = facts
+        .output_facts
+        .as_ref()
+        .expect("the Polonius output was not computed");
+    w.write(
+        "errors",
+        errors
+            .errors
+            .iter()
+            .flat_map(|(p, ls)| ls.iter().map(move |l| vec![w.point(p), w.atom(l)])),
+    );
+    w.write(
+        "subset_errors",
+        errors.subset_errors.iter().flat_map(|(p, os)| {
+            os.iter()
+                .map(move |(o1, o2)| vec![w.point(p), w.atom(o1), w.atom(o2)])
+        }),
+    );
+    w.write(
+        "move_errors",
+        errors
+            .move_errors
+            .iter()
+            .flat_map(|(p, ms)| ms.iter().map(move |m| vec![w.point(p), w.atom(m)])),
+    );
+
+    // rustc computes its output without the intermediate relations, so compute our own
+    let output = Output::compute(input, Algorithm::Naive, true);
+    w.write(
+        "loan_live_at",
+        output
+            .loan_live_at
+            .iter()
+            .flat_map(|(p, ls)| ls.iter().map(move |l| vec![w.atom(l), w.point(p)])),
+    );
+    w.write(
+        "origin_live_on_entry",
+        output
+            .origin_live_on_entry
+            .iter()
+            .flat_map(|(p, os)| os.iter().map(move |o| vec![w.atom(o), w.point(p)])),
+    );
+    w.write(
+        "origin_contains_loan_at",
+        output.origin_contains_loan_at.iter().flat_map(|(p, map)| {
+            map.iter().flat_map(move |(o, ls)| {
+                ls.iter()
+                    .map(move |l| vec![w.atom(o), w.atom(l), w.point(p)])
+            })
+        }),
+    );
+    w.write(
+        "subset",
+        output.subset.iter().flat_map(|(p, map)| {
+            map.iter().flat_map(move |(o1, o2s)| {
+                o2s.iter()
+                    .map(move |o2| vec![w.atom(o1), w.atom(o2), w.point(p)])
+            })
+        }),
+    );
+    w.write(
+        "var_live_on_entry",
+        output
+            .var_live_on_entry
+            .iter()
+            .flat_map(|(p, vs)| vs.iter().map(move |v| vec![w.atom(v), w.point(p)])),
+    );
+
+    let forged_atoms = ForgedAtoms::new(def_id, facts, forged);
+    w.write(
+        "forged_point",
+        forged_atoms.points.iter().map(|p| vec![w.point(p)]),
+    );
+    w.write(
+        "forged_loan",
+        forged_atoms.loans.iter().map(|l| vec![w.atom(l)]),
+    );
+    w.write(
+        "forged_origin",
+        forged_atoms
+            .origins
+            .iter()
+            .map(|(o, id)| vec![w.atom(o), w.atom(id)]),
+    );
+    println!(
+        "[log] facts for {} written to {}",
+        function,
+        w.dir.display()
+    );
+}
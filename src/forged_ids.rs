@@ -0,0 +1,98 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Crate-wide registry of forged identifiers.
+//!
+//! Forged borrows and locals are built with erased regions, as MIR building does, so borrowck
+//! renumbers them into region variables of their body like any others, and two bodies will
+//! share region vids. Once borrowck has run, each region variable which only exists because of
+//! forged code is given a crate-wide `ForgedRegionId` from a single counter, so that ids of
+//! different bodies never collide and any analysis, across bodies too, can ask whether a
+//! region is forged. Bodies may be borrow checked concurrently under rustc's parallel
+//! front-end, hence the atomic counter and the lock.
+//!
+//! DefIds are not forged: forged calls go to the injected havoc helper.
+
+use crate::cleanup::ForgedItems;
+use rustc_borrowck::consumers::BodyWithBorrowckFacts;
+use rustc_hash::FxHashMap;
+use rustc_middle::ty::{self, RegionVid, TyCtxt};
+use rustc_span::def_id::LocalDefId;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+/// A forged region, unique across the crate
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct ForgedRegionId(u32);
+
+impl fmt::Debug for ForgedRegionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "forged{}", self.0)
+    }
+}
+
+static NEXT_FORGED_REGION: AtomicU32 = AtomicU32::new(0);
+
+/// Forged regions of every rewritten body borrowck has run on
+static FORGED_REGIONS: LazyLock<
+    Mutex<FxHashMap<LocalDefId, FxHashMap<RegionVid, ForgedRegionId>>>,
+> = LazyLock::new(Default::default);
+
+/// Records the regions borrowck gave the forged code of a body: those of the loans forged
+/// code takes, and those renumbered into the types of forged locals. A body borrow checked
+/// again keeps the ids of the regions it forged before.
+pub(crate) fn record_forged_regions<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    def_id: LocalDefId,
+    facts: &BodyWithBorrowckFacts<'tcx>,
+    forged: &ForgedItems,
+) {
+    let mut vids = vec![];
+    for borrow in facts.borrow_set.location_map.values() {
+        if forged.is_mentioned_at(&facts.body, borrow.reserve_location) {
+            vids.push(borrow.region);
+        }
+    }
+    for local in forged.locals.iter() {
+        tcx.for_each_free_region(&facts.body.local_decls[*local].ty, |region| {
+            if let ty::ReVar(vid) = region.kind() {
+                vids.push(vid);
+            }
+        });
+    }
+
+    let mut registry = FORGED_REGIONS.lock().unwrap();
+    let previous = registry.remove(&def_id).unwrap_or_default();
+    let mut regions = FxHashMap::default();
+    for vid in vids {
+        regions.entry(vid).or_insert_with(|| {
+            previous.get(&vid).copied().unwrap_or_else(|| {
+                ForgedRegionId(NEXT_FORGED_REGION.fetch_add(1, Ordering::Relaxed))
+            })
+        });
+    }
+    registry.insert(def_id, regions);
+}
+
+/// The forged regions recorded for a body, with their crate-wide ids, if borrowck has run on it
+pub(crate) fn forged_regions(def_id: LocalDefId) -> FxHashMap<RegionVid, ForgedRegionId> {
+    FORGED_REGIONS
+        .lock()
+        .unwrap()
+        .get(&def_id)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Is the region variable of the body one borrowck gave to forged code?
+pub(crate) fn is_forged_region(def_id: LocalDefId, vid: RegionVid) -> bool {
+    FORGED_REGIONS
+        .lock()
+        .unwrap()
+        .get(&def_id)
+        .is_some_and(|regions| regions.contains_key(&vid))
+}
@@ -0,0 +1,101 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Ghost code: locals and statements on the real path which borrowck sees like any other,
+//! but which are stripped with the rest of the forged code before the body is lowered further.
+//!
+//! Ghost code lets a client such as Prusti model state the program does not have, e.g. a
+//! snapshot of a place, or a pledge kept as a reference which must outlive some point. It
+//! must not change what the real code does: it may read and borrow real places, but not
+//! assign to, move out of, drop or end the storage of them. `verify` checks this on the
+//! rewritten body; a body failing it is reported as an error and borrow checked unmodified.
+//! Stripping then asserts that nothing forged survives, ghost code included.
+
+use rustc_hash::FxHashSet;
+use rustc_middle::mir::visit::{MutatingUseContext, NonMutatingUseContext, PlaceContext, Visitor};
+use rustc_middle::mir::{BasicBlock, Body, Local, Location, Place, TerminatorKind};
+
+/// The ghost locals of a body, and the blocks holding its ghost statements
+#[derive(Debug, Default)]
+pub(crate) struct GhostCode {
+    pub locals: FxHashSet<Local>,
+    pub blocks: Vec<BasicBlock>,
+}
+
+/// Checks a single ghost statement
+struct GhostChecker<'a> {
+    ghost: &'a GhostCode,
+    mentions_ghost: bool,
+    violation: Option<String>,
+}
+
+impl<'a> GhostChecker<'a> {
+    /// Is the place ghost state, rather than real state reached through a ghost reference?
+    fn is_ghost_state(&self, place: Place<'_>) -> bool {
+        self.ghost.locals.contains(&place.local) && !place.is_indirect()
+    }
+}
+
+impl<'a, 'tcx> Visitor<'tcx> for GhostChecker<'a> {
+    fn visit_place(&mut self, place: &Place<'tcx>, context: PlaceContext, location: Location) {
+        let touches_real_state = match context {
+            PlaceContext::NonMutatingUse(NonMutatingUseContext::Move) => {
+                !self.is_ghost_state(*place)
+            }
+            PlaceContext::MutatingUse(
+                MutatingUseContext::Borrow | MutatingUseContext::AddressOf,
+            )
+            | PlaceContext::NonMutatingUse(_)
+            | PlaceContext::NonUse(_) => false,
+            PlaceContext::MutatingUse(_) => !self.is_ghost_state(*place),
+        };
+        if touches_real_state && self.violation.is_none() {
+            self.violation = Some(format!("{:?} of real place {:?}", context, place));
+        }
+        self.super_place(place, context, location);
+    }
+
+    fn visit_local(&mut self, local: Local, context: PlaceContext, _location: Location) {
+        let is_ghost = self.ghost.locals.contains(&local);
+        self.mentions_ghost |= is_ghost;
+        if matches!(context, PlaceContext::NonUse(_)) && !is_ghost && self.violation.is_none() {
+            self.violation = Some(format!("{:?} of real local {:?}", context, local));
+        }
+    }
+}
+
+/// Checks that the ghost statements of a body leave its real state alone, and can be
+/// stripped: each must mention a ghost local, as that is how stripping recognises them
+pub(crate) fn verify(body: &Body<'_>, ghost: &GhostCode) -> Result<(), String> {
+    for block in ghost.blocks.iter() {
+        let data = &body.basic_blocks[*block];
+        for (statement_index, statement) in data.statements.iter().enumerate() {
+            let location = Location {
+                block: *block,
+                statement_index,
+            };
+            let mut checker = GhostChecker {
+                ghost,
+                mentions_ghost: false,
+                violation: None,
+            };
+            checker.visit_statement(statement, location);
+            if let Some(violation) = checker.violation {
+                return Err(format!("ghost statement at {:?} has a {}", location, violation));
+            }
+            if !checker.mentions_ghost {
+                return Err(format!(
+                    "ghost statement at {:?} mentions no ghost local, so would not be stripped",
+                    location
+                ));
+            }
+        }
+        if !matches!(data.terminator().kind, TerminatorKind::Goto { .. }) {
+            return Err(format!("ghost block {:?} does not rejoin the real path", block));
+        }
+    }
+    Ok(())
+}
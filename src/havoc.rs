@@ -0,0 +1,66 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The havoc helper: a real generic function which forged code can call to produce a
+//! value of any type.
+//!
+//! It is parsed into the crate root before expansion and marked with `#[analyzer::havoc]`
+//! (the `analyzer` tool is registered through `-Zcrate-attr`), so its DefId can be found
+//! again once the crate has been lowered. Calls to it only appear in forged code.
+
+use rustc_ast::ast;
+use rustc_middle::ty::TyCtxt;
+use rustc_parse::parser::ForceCollect;
+use rustc_session::parse::ParseSess;
+use rustc_span::def_id::DefId;
+use rustc_span::symbol::Symbol;
+use rustc_span::FileName;
+use std::sync::OnceLock;
+
+const HAVOC_SOURCE: &str = "
+#[allow(dead_code)]
+#[analyzer::havoc]
+fn analyzer_havoc<T>() -> T {
+    loop {}
+}
+";
+
+/// Adds the havoc helper to the items of the crate root
+pub(crate) fn inject_havoc(parse_sess: &ParseSess, krate: &mut ast::Crate) {
+    let mut parser = rustc_parse::new_parser_from_source_str(
+        parse_sess,
+        FileName::Custom("analyzer_havoc".to_owned()),
+        HAVOC_SOURCE.to_owned(),
+    );
+    let item = parser
+        .parse_item(ForceCollect::No)
+        .map_err(|mut err| err.emit())
+        .ok()
+        .flatten()
+        .expect("the havoc helper does not parse");
+    krate.items.push(item);
+}
+
+/// DefId of the havoc helper, found once per compilation
+static HAVOC_DEF_ID: OnceLock<DefId> = OnceLock::new();
+
+/// DefId of the havoc helper injected into the local crate
+///
+/// Every body asks for it, so the crate's items are only searched for the attribute once.
+pub(crate) fn havoc_def_id(tcx: TyCtxt<'_>) -> DefId {
+    *HAVOC_DEF_ID.get_or_init(|| {
+        let path = [Symbol::intern("analyzer"), Symbol::intern("havoc")];
+        tcx.hir()
+            .items()
+            .map(|item| item.owner_id.to_def_id())
+            .find(|def_id| {
+                tcx.get_attrs_unchecked(*def_id)
+                    .iter()
+                    .any(|attr| attr.path_matches(&path))
+            })
+            .expect("the havoc helper was not injected")
+    })
+}
@@ -0,0 +1,210 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Initialisation of the places of an unmodified body.
+//!
+//! Borrowck rejects any use of a place which is uninitialised on some path to it, so tests
+//! which use such a place are trivially rejected: "use of moved value" and the like. These
+//! tests are reported as uninitialised instead of being run.
+
+use rustc_hash::FxHashSet;
+use rustc_index::IndexVec;
+use rustc_middle::mir::visit::Visitor;
+use rustc_middle::mir::{
+    BasicBlock, Body, Location, Operand, Place, ProjectionElem, Statement, StatementKind,
+    Terminator, TerminatorKind, START_BLOCK,
+};
+use rustc_middle::ty::{self, TyCtxt};
+use rustc_target::abi::FieldIdx;
+use std::collections::VecDeque;
+
+/// Is prefix the place itself or a place containing it?
+fn is_prefix<'tcx>(prefix: Place<'tcx>, place: Place<'tcx>) -> bool {
+    prefix.local == place.local && place.projection.starts_with(prefix.projection)
+}
+
+/// Places moved out of by a statement or terminator
+#[derive(Default)]
+struct MovedPlaces<'tcx>(Vec<Place<'tcx>>);
+
+impl<'tcx> Visitor<'tcx> for MovedPlaces<'tcx> {
+    fn visit_operand(&mut self, operand: &Operand<'tcx>, _location: Location) {
+        if let Operand::Move(place) = operand {
+            self.0.push(*place);
+        }
+    }
+}
+
+/// Places uninitialised on some path to each block's entry: never initialised, moved out of,
+/// dropped, or whose storage is dead
+///
+/// A place's parts are not listed alongside it, so a place is uninitialised if it, a place
+/// containing it, or one of its parts is listed.
+pub(crate) struct MaybeUninitialised<'tcx> {
+    tcx: TyCtxt<'tcx>,
+    entry_sets: IndexVec<BasicBlock, FxHashSet<Place<'tcx>>>,
+}
+
+impl<'tcx> MaybeUninitialised<'tcx> {
+    pub fn compute(tcx: TyCtxt<'tcx>, body: &Body<'tcx>) -> Self {
+        let mut entry_sets = IndexVec::from_elem(FxHashSet::default(), &body.basic_blocks);
+        // Every local but the arguments starts uninitialised, the return place included
+        let args: Vec<_> = body.args_iter().collect();
+        entry_sets[START_BLOCK] = body
+            .local_decls
+            .indices()
+            .filter(|local| !args.contains(local))
+            .map(Place::from)
+            .collect();
+
+        let mut queue: VecDeque<_> = body.basic_blocks.indices().collect();
+        while let Some(block) = queue.pop_front() {
+            let data = &body.basic_blocks[block];
+            let mut state = entry_sets[block].clone();
+            for (statement_index, statement) in data.statements.iter().enumerate() {
+                let location = Location {
+                    block,
+                    statement_index,
+                };
+                Self::apply_statement(tcx, body, statement, location, &mut state);
+            }
+            let location = body.terminator_loc(block);
+            Self::apply_terminator(tcx, body, data.terminator(), location, &mut state);
+            for successor in data.terminator().successors() {
+                let entry_set = &mut entry_sets[successor];
+                let count = entry_set.len();
+                entry_set.extend(state.iter().copied());
+                if entry_set.len() != count {
+                    queue.push_back(successor);
+                }
+            }
+        }
+        MaybeUninitialised { tcx, entry_sets }
+    }
+
+    fn uninitialise(state: &mut FxHashSet<Place<'tcx>>, place: Place<'tcx>) {
+        state.retain(|listed| !is_prefix(place, *listed));
+        state.insert(place);
+    }
+
+    /// Initialising a part of an uninitialised place leaves the place uninitialised, through
+    /// its other parts: a listed place containing the part is split into its fields down to
+    /// the part, as far as it goes through structs and tuples
+    fn initialise(
+        tcx: TyCtxt<'tcx>,
+        body: &Body<'tcx>,
+        state: &mut FxHashSet<Place<'tcx>>,
+        place: Place<'tcx>,
+    ) {
+        state.retain(|listed| !is_prefix(place, *listed));
+        let parents: Vec<_> = state
+            .iter()
+            .copied()
+            .filter(|listed| is_prefix(*listed, place))
+            .collect();
+        for parent in parents {
+            state.remove(&parent);
+            let mut current = parent;
+            for elem in place.projection[parent.projection.len()..].iter() {
+                let ProjectionElem::Field(field, _) = elem else {
+                    state.insert(current);
+                    break;
+                };
+                let field_tys: Vec<_> = match current.ty(body, tcx).ty.kind() {
+                    ty::Adt(adt, args) if adt.is_struct() => adt
+                        .non_enum_variant()
+                        .fields
+                        .iter()
+                        .map(|field_def| field_def.ty(tcx, args))
+                        .collect(),
+                    ty::Tuple(tys) => tys.iter().collect(),
+                    // Writing any field of a union initialises all of it
+                    ty::Adt(adt, _) if adt.is_union() => vec![],
+                    _ => {
+                        state.insert(current);
+                        break;
+                    }
+                };
+                for (index, field_ty) in field_tys.into_iter().enumerate() {
+                    if index != field.index() {
+                        state.insert(tcx.mk_place_field(
+                            current,
+                            FieldIdx::from_usize(index),
+                            field_ty,
+                        ));
+                    }
+                }
+                current = current.project_deeper(&[*elem], tcx);
+            }
+        }
+    }
+
+    fn apply_moves(state: &mut FxHashSet<Place<'tcx>>, moves: MovedPlaces<'tcx>) {
+        for place in moves.0 {
+            Self::uninitialise(state, place);
+        }
+    }
+
+    fn apply_statement(
+        tcx: TyCtxt<'tcx>,
+        body: &Body<'tcx>,
+        statement: &Statement<'tcx>,
+        location: Location,
+        state: &mut FxHashSet<Place<'tcx>>,
+    ) {
+        // Operands are moved out of before the assignment they feed
+        let mut moves = MovedPlaces::default();
+        moves.visit_statement(statement, location);
+        Self::apply_moves(state, moves);
+        match &statement.kind {
+            StatementKind::Assign(box (place, _)) => Self::initialise(tcx, body, state, *place),
+            StatementKind::StorageLive(local) | StatementKind::StorageDead(local) => {
+                Self::uninitialise(state, Place::from(*local))
+            }
+            _ => {}
+        }
+    }
+
+    fn apply_terminator(
+        tcx: TyCtxt<'tcx>,
+        body: &Body<'tcx>,
+        terminator: &Terminator<'tcx>,
+        location: Location,
+        state: &mut FxHashSet<Place<'tcx>>,
+    ) {
+        let mut moves = MovedPlaces::default();
+        moves.visit_terminator(terminator, location);
+        Self::apply_moves(state, moves);
+        match &terminator.kind {
+            // The destination is only initialised when the call returns, but initialising it
+            //  along the unwind edge too only makes us report fewer tests
+            TerminatorKind::Call { destination, .. } => {
+                Self::initialise(tcx, body, state, *destination)
+            }
+            TerminatorKind::Drop { place, .. } => Self::uninitialise(state, *place),
+            _ => {}
+        }
+    }
+
+    /// Is the place uninitialised on some path to the location, before its statement runs?
+    pub fn contains(&self, body: &Body<'tcx>, location: Location, place: Place<'tcx>) -> bool {
+        let mut state = self.entry_sets[location.block].clone();
+        let data = &body.basic_blocks[location.block];
+        for (statement_index, statement) in data.statements[..location.statement_index]
+            .iter()
+            .enumerate()
+        {
+            let location = Location {
+                block: location.block,
+                statement_index,
+            };
+            Self::apply_statement(self.tcx, body, statement, location, &mut state);
+        }
+        state
+            .iter()
+            .any(|listed| is_prefix(*listed, place) || is_prefix(place, *listed))
+    }
+}
@@ -0,0 +1,112 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Instrument mode: instead of hand-picked tests, the configured battery of tests at every
+//! location of every function, on every user variable.
+//!
+//! As in liveness plans and capabilities mode, a block's locations are those of its
+//! statements and that of its terminator, with a test at a location running before it.
+//!
+//! Tests which use a variable uninitialised on some path to them are left out, and only
+//! listed in the coverage, unless `--analysis-uninitialised=run`.
+//!
+//! The tests of a body are packed into it at once, as far as their verdicts can be told
+//! apart (see `verdicts`), relying on the location table of `BodyModifier` to find each
+//! original statement among the splits of the earlier tests.
+
+use crate::config::{self, PlaceSpec, TestSpec, UninitialisedTests};
+use crate::havoc;
+use crate::init::MaybeUninitialised;
+use crate::TestKind;
+use rustc_hir::def::DefKind;
+use rustc_middle::mir::{Body, Location, Place};
+use rustc_middle::ty::TyCtxt;
+use rustc_span::def_id::LocalDefId;
+use serde::{Deserialize, Serialize};
+
+/// What the instrumentation of a body covered
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Coverage {
+    /// Locations of the unmodified body outside cleanup blocks, statements and terminators,
+    /// each preceded by tests
+    pub locations: usize,
+    /// User variables, tested at every location
+    pub locals: Vec<String>,
+    /// Number of tests inserted
+    pub tests: usize,
+    /// `<location>: <local>` for each local whose tests were left out at a location, as it is
+    /// uninitialised on some path there
+    pub uninitialised: Vec<String>,
+}
+
+/// Tests to insert into a body, with what they cover, if the body is a function's
+pub(crate) fn plan<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    def_id: LocalDefId,
+    body: &Body<'tcx>,
+) -> Option<(Vec<TestSpec>, Coverage)> {
+    if !matches!(tcx.def_kind(def_id), DefKind::Fn | DefKind::AssocFn)
+        || def_id.to_def_id() == havoc::havoc_def_id(tcx)
+    {
+        return None;
+    }
+
+    let function = tcx.def_path_str(def_id.to_def_id());
+    let locals: Vec<_> = body
+        .local_decls
+        .iter_enumerated()
+        .filter(|(_, decl)| decl.is_user_variable())
+        .map(|(local, _)| local)
+        .collect();
+    let maybe_uninitialised = (config::config().uninitialised == UninitialisedTests::Report)
+        .then(|| MaybeUninitialised::compute(tcx, body));
+    let battery = &config::config().battery;
+    let mut specs = vec![];
+    let mut coverage = Coverage {
+        locals: locals.iter().map(|local| format!("{:?}", local)).collect(),
+        ..Default::default()
+    };
+
+    // Cleanup blocks only run while unwinding, and cannot hold a test's split
+    for (block, data) in body.basic_blocks.iter_enumerated() {
+        if data.is_cleanup {
+            continue;
+        }
+        for statement_index in 0..=data.statements.len() {
+            let location = Location {
+                block,
+                statement_index,
+            };
+            coverage.locations += 1;
+            for local in locals.iter() {
+                let uninitialised = maybe_uninitialised
+                    .as_ref()
+                    .is_some_and(|init| init.contains(body, location, Place::from(*local)));
+                if uninitialised && battery.iter().any(TestKind::needs_initialised) {
+                    coverage
+                        .uninitialised
+                        .push(format!("{:?}: {:?}", location, local));
+                }
+                let kinds = battery
+                    .iter()
+                    .filter(|kind| !uninitialised || !kind.needs_initialised());
+                specs.extend(kinds.map(|kind| TestSpec {
+                    function: function.clone(),
+                    location,
+                    place: PlaceSpec {
+                        local: *local,
+                        projection: vec![],
+                    },
+                    kind: *kind,
+                    outlives: None,
+                    activation: None,
+                }));
+            }
+        }
+    }
+    coverage.tests = specs.len();
+    Some((specs, coverage))
+}
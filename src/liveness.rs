@@ -0,0 +1,177 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Liveness of the locals of an unmodified body.
+//!
+//! A loan only constrains a test while some live local may still use it, so the references
+//! live at a test's location are the ones which can be responsible for its verdict. Liveness
+//! also places tests: a plan tests a place at every location where a given local is live.
+
+use crate::config::{LivePlan, TestSpec};
+use rustc_hash::FxHashMap;
+use rustc_index::bit_set::BitSet;
+use rustc_index::IndexVec;
+use rustc_middle::mir::visit::{MutatingUseContext, NonUseContext, PlaceContext, Visitor};
+use rustc_middle::mir::{BasicBlock, Body, Local, Location, VarDebugInfoContents};
+use rustc_middle::ty;
+use std::collections::VecDeque;
+
+/// Locals a statement or terminator defines, and those it uses
+///
+/// A local both used and defined, as in `_1 = move _1`, is used before it is defined.
+#[derive(Default)]
+struct DefsAndUses {
+    defs: Vec<Local>,
+    uses: Vec<Local>,
+}
+
+impl<'tcx> Visitor<'tcx> for DefsAndUses {
+    fn visit_local(&mut self, local: Local, context: PlaceContext, _location: Location) {
+        match context {
+            // Only whole locals get here with these contexts; writes to a projection are
+            //  uses of the local they project from
+            PlaceContext::MutatingUse(
+                MutatingUseContext::Store
+                | MutatingUseContext::Call
+                | MutatingUseContext::AsmOutput
+                | MutatingUseContext::Yield,
+            )
+            | PlaceContext::NonUse(NonUseContext::StorageLive | NonUseContext::StorageDead) => {
+                self.defs.push(local)
+            }
+            PlaceContext::NonUse(NonUseContext::VarDebugInfo) => {}
+            _ => self.uses.push(local),
+        }
+    }
+}
+
+impl DefsAndUses {
+    fn apply(self, live: &mut BitSet<Local>) {
+        for local in self.defs {
+            live.remove(local);
+        }
+        for local in self.uses {
+            live.insert(local);
+        }
+    }
+}
+
+/// Locals live at the exit of each block, i.e. which some path from there may still use
+pub(crate) struct LiveLocals {
+    exit_sets: IndexVec<BasicBlock, BitSet<Local>>,
+}
+
+impl LiveLocals {
+    pub fn compute(body: &Body<'_>) -> Self {
+        let mut exit_sets =
+            IndexVec::from_elem(BitSet::new_empty(body.local_decls.len()), &body.basic_blocks);
+        let predecessors = body.basic_blocks.predecessors();
+
+        let mut queue: VecDeque<_> = body.basic_blocks.indices().rev().collect();
+        while let Some(block) = queue.pop_front() {
+            let entry_set = Self::before(body, &exit_sets[block], body.terminator_loc(block));
+            let entry_set = Self::before_statements(body, entry_set, block, 0);
+            for predecessor in predecessors[block].iter() {
+                if exit_sets[*predecessor].union(&entry_set) {
+                    queue.push_back(*predecessor);
+                }
+            }
+        }
+        LiveLocals { exit_sets }
+    }
+
+    /// Locals live before the terminator at location, given those live after it
+    fn before(body: &Body<'_>, exit_set: &BitSet<Local>, location: Location) -> BitSet<Local> {
+        let mut live = exit_set.clone();
+        let mut defs_and_uses = DefsAndUses::default();
+        defs_and_uses.visit_terminator(body.basic_blocks[location.block].terminator(), location);
+        defs_and_uses.apply(&mut live);
+        live
+    }
+
+    /// Locals live before the statement at first, given those live after the block's last
+    fn before_statements(
+        body: &Body<'_>,
+        mut live: BitSet<Local>,
+        block: BasicBlock,
+        first: usize,
+    ) -> BitSet<Local> {
+        let statements = &body.basic_blocks[block].statements;
+        for statement_index in (first..statements.len()).rev() {
+            let location = Location {
+                block,
+                statement_index,
+            };
+            let mut defs_and_uses = DefsAndUses::default();
+            defs_and_uses.visit_statement(&statements[statement_index], location);
+            defs_and_uses.apply(&mut live);
+        }
+        live
+    }
+
+    /// Locals live before the statement or terminator at the location runs
+    pub fn at(&self, body: &Body<'_>, location: Location) -> BitSet<Local> {
+        let live = Self::before(
+            body,
+            &self.exit_sets[location.block],
+            body.terminator_loc(location.block),
+        );
+        if location.statement_index == body.basic_blocks[location.block].statements.len() {
+            return live;
+        }
+        Self::before_statements(body, live, location.block, location.statement_index)
+    }
+
+    /// Live locals at the location whose types carry regions, named after their user
+    /// variables where they have one
+    pub fn live_references(&self, body: &Body<'_>, location: Location) -> Vec<String> {
+        let names: FxHashMap<Local, String> = body
+            .var_debug_info
+            .iter()
+            .filter_map(|info| match &info.value {
+                VarDebugInfoContents::Place(place) => {
+                    Some((place.as_local()?, format!("{} ({:?})", info.name, place)))
+                }
+                _ => None,
+            })
+            .collect();
+        self.at(body, location)
+            .iter()
+            .filter(|local| {
+                body.local_decls[*local]
+                    .ty
+                    .walk()
+                    .any(|arg| matches!(arg.unpack(), ty::GenericArgKind::Lifetime(_)))
+            })
+            .map(|local| {
+                names
+                    .get(&local)
+                    .cloned()
+                    .unwrap_or_else(|| format!("{:?}", local))
+            })
+            .collect()
+    }
+
+    /// The tests of a plan: one at every location outside cleanup blocks where its local is
+    /// live, terminators included, as in instrument and capabilities modes
+    pub fn expand(&self, body: &Body<'_>, plan: &LivePlan) -> Vec<TestSpec> {
+        body.basic_blocks
+            .iter_enumerated()
+            .filter(|(_, data)| !data.is_cleanup)
+            .flat_map(|(block, data)| {
+                (0..=data.statements.len()).map(move |statement_index| Location {
+                    block,
+                    statement_index,
+                })
+            })
+            .filter(|location| self.at(body, *location).contains(plan.local))
+            .map(|location| TestSpec {
+                location,
+                ..plan.test.clone()
+            })
+            .collect()
+    }
+}
@@ -15,23 +15,33 @@
 // https://github.com/rust-lang/rust/blob/master/src/test/run-make-fulldeps/obtain-borrowck/driver.rs
 // https://github.com/viperproject/prusti-dev/blob/master/analysis/src/bin/analysis-driver.rs
 
+extern crate polonius_engine;
+extern crate rustc_ast;
 extern crate rustc_ast_pretty;
+extern crate rustc_borrowck;
 extern crate rustc_data_structures;
 extern crate rustc_driver;
 extern crate rustc_error_codes;
 extern crate rustc_errors;
 extern crate rustc_hash;
 extern crate rustc_hir;
+extern crate rustc_index;
+extern crate rustc_infer;
 extern crate rustc_interface;
 extern crate rustc_middle;
+extern crate rustc_parse;
 extern crate rustc_session;
 extern crate rustc_span;
+extern crate rustc_target;
 
 use itertools::max;
 use rustc_ast_pretty::pprust::item_to_string;
 use rustc_data_structures::steal::Steal;
 use rustc_driver::Compilation;
 use rustc_errors::registry;
+use rustc_hash::FxHashSet;
+use rustc_index::bit_set::BitSet;
+use rustc_infer::infer::{RegionVariableOrigin, TyCtxtInferExt};
 use rustc_middle::middle::provide;
 use rustc_middle::mir::ClearCrossCrate;
 use rustc_middle::mir::Location;
@@ -42,21 +52,27 @@ use rustc_middle::mir::{
     Rvalue, Statement, StatementKind, Terminator, TerminatorKind, OUTERMOST_SOURCE_SCOPE, BorrowKind, MutBorrowKind, Constant, CallSource, UnwindAction, UnwindTerminateReason, ConstantKind
 };
 use rustc_middle::mir::interpret::ConstValue;
+use rustc_middle::mir::UserTypeProjection;
 use rustc_middle::query::queries::mir_built::{self, ProvidedValue};
 use rustc_middle::query::Providers;
 use rustc_middle::ty;
+use rustc_middle::ty::CanonicalUserTypeAnnotation;
 use rustc_middle::ty::Region;
 use rustc_middle::ty::RegionVid;
 use rustc_middle::ty::Ty;
 use rustc_middle::ty::TyCtxt;
-use rustc_session::config::{self, CheckCfg};
+use rustc_middle::ty::UserType;
+use rustc_session::config::CheckCfg;
 use rustc_session::EarlyErrorHandler;
 use rustc_span::def_id::LocalDefId;
+use rustc_span::def_id::LOCAL_CRATE;
 use rustc_span::def_id::DefId;
 use rustc_span::source_map;
 use rustc_span::DUMMY_SP;
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::env;
+use std::ffi::OsStr;
 use std::fs;
 use std::mem;
 use std::sync::LazyLock;
@@ -64,9 +80,29 @@ use std::thread::current;
 use std::vec;
 use std::{path, process, str};
 
+mod capabilities;
+mod cleanup;
+mod config;
+mod differential;
+mod explain;
+mod facts;
+mod forged_ids;
+mod ghost;
+mod havoc;
+mod init;
+mod instrument;
+mod liveness;
+mod reborrows;
+mod report;
+mod runner;
+mod search;
+mod verdicts;
+
 #[allow(dead_code)]
 struct OurCompilerCalls {
     args: Vec<String>,
+    /// Name of the crate being compiled, once it is known
+    crate_name: Option<String>,
 }
 
 const FORGED_SOURCE_INFO: SourceInfo = SourceInfo {
@@ -74,8 +110,6 @@ const FORGED_SOURCE_INFO: SourceInfo = SourceInfo {
     scope: OUTERMOST_SOURCE_SCOPE,
 };
 
-const MAX_FORGED_REGION_INDEX: u32 = 0xFFFF_FF00;
-const MAX_FORGED_DEF_ID: u32 = 0xFFFF_FF00;
 
 struct BodyModifier<'mir, 'tcx> {
     tcx: TyCtxt<'tcx>,
@@ -86,12 +120,34 @@ struct BodyModifier<'mir, 'tcx> {
     /// Mapping from original locations to locations in the current MIR
     location_table: BTreeMap<Location, Location>,
 
-    /// Index for generating free regions
-    next_free_region: u32,
+    /// Number of locals in the original MIR; every local at or after this index is forged
+    original_local_count: usize,
+
+    /// Number of blocks in the original MIR; every block at or after this index is forged
+    original_block_count: usize,
+
+    /// Splits allocated so far, in the order they were made
+    splits: Vec<Split>,
+
+    /// DefIds which only forged code calls
+    forged_def_ids: Vec<DefId>,
+
+    /// Unwind behaviour of the calls forged by insert_test
+    pub forged_unwind: ForgedUnwind,
 
-    /// Index for generating new DefIds
-    next_free_def_id: u32,
+    /// Ghost locals, and the inline blocks holding ghost statements
+    ghost: ghost::GhostCode,
+}
 
+/// Record of a single split, sufficient to undo it
+#[derive(Clone, Copy, Debug)]
+struct Split {
+    /// Block which was split, and which now ends in the jumping terminator
+    block: BasicBlock,
+    /// Block holding the statements moved out of the split block
+    kont_block: BasicBlock,
+    /// Block jumped into by the split
+    test_block: BasicBlock,
 }
 
 /// Kinds of splits we can allocate
@@ -101,9 +157,176 @@ enum SplitKind {
     /// adds an goto into a block which goto's back into regular control flow
     Inline,
     /// adds a FalseEdge into a block which goto's back into the continuation
+    /// Populated by the approximate_* methods of BodyModifier
     Approximator,
 }
 
+/// How forged calls unwind
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ForgedUnwind {
+    /// The call cannot unwind
+    Unreachable,
+    /// Unwinding out of the call aborts
+    Terminate,
+    /// Unwinding continues to the caller without running any cleanup
+    Continue,
+    /// Unwinding enters a fresh cleanup block, which resumes unwinding unless populated
+    Cleanup,
+}
+
+/// Kinds of tests which can be injected into a SplitKind::Test block
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum TestKind {
+    MoveOut,
+    MoveIn,
+    SharedBorrow,
+    ShallowBorrow,
+    MutBorrow,
+    TwoPhaseBorrow,
+    ClosureCaptureBorrow,
+    RawConst,
+    RawMut,
+    Outlives,
+    Write,
+    Read,
+    StorageDead,
+    Drop,
+    TwoPhaseActivation,
+}
+
+/// Which of the errors borrowck may report for a test the Polonius output facts express
+///
+/// The facts hold loan conflicts, uses of uninitialised places and region errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FactsCoverage {
+    /// Every error the test can cause
+    Complete,
+    /// All but errors which only depend on the place, whatever the test's location: mutability
+    /// errors, and moves out of borrowed places
+    AllButPlaceErrors,
+    /// Not errors which depend on the location too, such as assigning twice to an immutable local
+    Partial,
+}
+
+/// Ways a test can take a reference or a raw pointer to a place
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ForgedBorrowKind {
+    /// `&p`
+    Shared,
+    /// The borrow match guards take of the scrutinee, which only conflicts with writes
+    Shallow,
+    /// `&mut p`
+    Mut,
+    /// `&mut p` for an autoref'd method receiver, not active until its first use
+    TwoPhaseMut,
+    /// The unique immutable borrow a closure takes of a captured `*x` with `x: &mut T`
+    ClosureCapture,
+    /// `&raw const p`
+    RawConst,
+    /// `&raw mut p`
+    RawMut,
+}
+
+impl TestKind {
+    /// The borrow taken by a borrow test
+    pub fn borrow_kind(&self) -> Option<ForgedBorrowKind> {
+        match self {
+            TestKind::SharedBorrow => Some(ForgedBorrowKind::Shared),
+            TestKind::ShallowBorrow => Some(ForgedBorrowKind::Shallow),
+            TestKind::MutBorrow => Some(ForgedBorrowKind::Mut),
+            TestKind::TwoPhaseBorrow => Some(ForgedBorrowKind::TwoPhaseMut),
+            TestKind::ClosureCaptureBorrow => Some(ForgedBorrowKind::ClosureCapture),
+            TestKind::RawConst => Some(ForgedBorrowKind::RawConst),
+            TestKind::RawMut => Some(ForgedBorrowKind::RawMut),
+            TestKind::MoveOut
+            | TestKind::MoveIn
+            | TestKind::Outlives
+            | TestKind::Write
+            | TestKind::Read
+            | TestKind::StorageDead
+            | TestKind::Drop
+            | TestKind::TwoPhaseActivation => None,
+        }
+    }
+
+    /// Which of the errors borrowck may report for the test the Polonius output facts express
+    pub fn facts_coverage(&self) -> FactsCoverage {
+        match self {
+            TestKind::SharedBorrow
+            | TestKind::ShallowBorrow
+            | TestKind::RawConst
+            | TestKind::Read
+            | TestKind::StorageDead => FactsCoverage::Complete,
+            TestKind::MoveOut
+            | TestKind::MutBorrow
+            | TestKind::TwoPhaseBorrow
+            | TestKind::ClosureCaptureBorrow
+            | TestKind::RawMut
+            | TestKind::Drop => FactsCoverage::AllButPlaceErrors,
+            // Outlives tests fail on region errors, which borrowck reports for the whole body
+            TestKind::MoveIn
+            | TestKind::Write
+            | TestKind::Outlives
+            | TestKind::TwoPhaseActivation => FactsCoverage::Partial,
+        }
+    }
+
+    /// Does the test use its place, so that borrowck rejects it if the place is uninitialised?
+    /// Writes and storage-dead do not, and drops of uninitialised places are elaborated away.
+    pub fn needs_initialised(&self) -> bool {
+        !matches!(
+            self,
+            TestKind::MoveIn | TestKind::Write | TestKind::StorageDead | TestKind::Drop
+        )
+    }
+}
+
+impl ForgedBorrowKind {
+    /// Is the result a raw pointer, which carries no region?
+    pub fn is_raw(&self) -> bool {
+        matches!(self, ForgedBorrowKind::RawConst | ForgedBorrowKind::RawMut)
+    }
+
+    /// Mutability of the reference or pointer type; unique immutable borrows are typed `&mut`
+    pub fn mutability(&self) -> Mutability {
+        match self {
+            ForgedBorrowKind::Shared | ForgedBorrowKind::Shallow | ForgedBorrowKind::RawConst => {
+                Mutability::Not
+            }
+            ForgedBorrowKind::Mut
+            | ForgedBorrowKind::TwoPhaseMut
+            | ForgedBorrowKind::ClosureCapture
+            | ForgedBorrowKind::RawMut => Mutability::Mut,
+        }
+    }
+
+    /// Type of the test local holding the borrow of a place of type pointee
+    pub fn ty<'tcx>(&self, tcx: TyCtxt<'tcx>, region: Region<'tcx>, pointee: Ty<'tcx>) -> Ty<'tcx> {
+        match (self.is_raw(), self.mutability()) {
+            (true, Mutability::Not) => Ty::new_imm_ptr(tcx, pointee),
+            (true, Mutability::Mut) => Ty::new_mut_ptr(tcx, pointee),
+            (false, Mutability::Not) => Ty::new_imm_ref(tcx, region, pointee),
+            (false, Mutability::Mut) => Ty::new_mut_ref(tcx, region, pointee),
+        }
+    }
+
+    /// The rvalue taking the borrow of p
+    pub fn rvalue<'tcx>(&self, region: Region<'tcx>, p: Place<'tcx>) -> Rvalue<'tcx> {
+        let kind = match self {
+            ForgedBorrowKind::Shared => BorrowKind::Shared,
+            ForgedBorrowKind::Shallow => BorrowKind::Shallow,
+            ForgedBorrowKind::Mut => BorrowKind::Mut { kind: MutBorrowKind::Default },
+            ForgedBorrowKind::TwoPhaseMut => BorrowKind::Mut { kind: MutBorrowKind::TwoPhaseBorrow },
+            ForgedBorrowKind::ClosureCapture => BorrowKind::Mut { kind: MutBorrowKind::ClosureCapture },
+            ForgedBorrowKind::RawConst | ForgedBorrowKind::RawMut => {
+                return Rvalue::AddressOf(self.mutability(), p)
+            }
+        };
+        Rvalue::Ref(region, kind, p)
+    }
+}
+
 impl SplitKind {
     /// Terminator to jump into the split
     pub fn jumping_terminator<'tcx>(
@@ -162,12 +385,19 @@ impl<'mir, 'tcx: 'mir> BodyModifier<'mir, 'tcx> {
             }
         }
 
+        let original_local_count = body.local_decls.len();
+        let original_block_count = body.basic_blocks.len();
+
         Self {
             tcx,
             body,
             location_table,
-            next_free_region: MAX_FORGED_REGION_INDEX,
-            next_free_def_id: MAX_FORGED_DEF_ID,
+            original_local_count,
+            original_block_count,
+            splits: vec![],
+            ghost: Default::default(),
+            forged_def_ids: vec![],
+            forged_unwind: ForgedUnwind::Terminate,
         }
     }
 
@@ -183,19 +413,13 @@ impl<'mir, 'tcx: 'mir> BodyModifier<'mir, 'tcx> {
         }
     }
 
-    fn forge_def_id(&mut self) -> DefId {
-        //FIXME: doesn't check that this isn't already a DefIdIndex
-        let r = self.next_free_def_id;
-        self.next_free_def_id -= 1;
-        DefId::local(r.into())
-    }
-
-    fn fresh_region(&mut self) -> Region<'tcx> {
-        /* all regions in this phase of MIR appear to be ReErased; if we give regions fresh
-         * indicies will the borrow checker work everything out? */
-        let r = self.next_free_region;
-        self.next_free_region -= 1;
-        Region::new_var(self.tcx, RegionVid::from_u32(r))
+    /// DefId of the havoc helper, which forged code calls to produce a value of any type
+    fn havoc_def_id(&mut self) -> DefId {
+        let def_id = havoc::havoc_def_id(self.tcx);
+        if !self.forged_def_ids.contains(&def_id) {
+            self.forged_def_ids.push(def_id);
+        }
+        def_id
     }
 
     pub(crate) fn show_all_types(&self) {
@@ -218,6 +442,47 @@ impl<'mir, 'tcx: 'mir> BodyModifier<'mir, 'tcx> {
         return local;
     }
 
+    /// Allocates a ghost local, which borrowck sees like any other but which is stripped with
+    /// the rest of the forged code
+    /// Its storage is live throughout the body unless ghost statements mark it otherwise.
+    pub fn allocate_ghost_local(&mut self, ty: Ty<'tcx>) -> Local {
+        let local = self.allocate_fresh_local(ty);
+        self.ghost.locals.insert(local);
+        local
+    }
+
+    /// Inserts ghost statements on the real path, just before an original location
+    /// Each statement must mention a ghost local, and may only read or borrow real places;
+    /// ghost::verify checks both once the body is rewritten.
+    pub fn insert_ghost_statements(&mut self, loc: &Location, kinds: Vec<StatementKind<'tcx>>) {
+        let ghost_block = self.allocate_split_branch_before(loc, SplitKind::Inline);
+        let statements = kinds
+            .into_iter()
+            .map(|kind| Statement {
+                source_info: FORGED_SOURCE_INFO,
+                kind,
+            })
+            .collect::<_>();
+        self.set_statements(ghost_block, statements);
+        self.ghost.blocks.push(ghost_block);
+    }
+
+    /// Inserts a ghost assignment to a ghost local just before an original location
+    pub fn insert_ghost_assignment(&mut self, loc: &Location, ghost: Local, rvalue: Rvalue<'tcx>) {
+        assert!(
+            self.ghost.locals.contains(&ghost),
+            "{:?} is not a ghost local",
+            ghost
+        );
+        let place = self.local_to_place(ghost);
+        self.insert_ghost_statements(loc, vec![StatementKind::Assign(Box::new((place, rvalue)))]);
+    }
+
+    /// Checks the ghost code inserted so far, see ghost::verify
+    pub fn verify_ghost_code(&self) -> Result<(), String> {
+        ghost::verify(self.body, &self.ghost)
+    }
+
     /// Generates the test code to move out of a place
     pub fn test_move_out(&mut self, p: Place<'tcx>) -> Vec<Statement<'tcx>> {
         /// test_local nas no projections, so we take the Ty field of p' PlaceTy for it's type
@@ -238,62 +503,257 @@ impl<'mir, 'tcx: 'mir> BodyModifier<'mir, 'tcx> {
 
 
     pub fn test_mut_borrow(&mut self, p: Place<'tcx>) -> Vec<Statement<'tcx>> {
-        self.test_borrow(true, p)
+        self.test_borrow(ForgedBorrowKind::Mut, p, None)
     }
 
     pub fn test_shared_borrow(&mut self, p: Place<'tcx>) -> Vec<Statement<'tcx>> {
-        self.test_borrow(false, p)
+        self.test_borrow(ForgedBorrowKind::Shared, p, None)
     }
 
 
-    /// Generates the test code to borrow a place
-    fn test_borrow(&mut self, mutable: bool, p: Place<'tcx>) -> Vec<Statement<'tcx>> {
+    /// Generates the test code to borrow a place, or to take a raw pointer to it
+    /// As in freshly built MIR, the regions of the borrow and of the test local are erased;
+    /// borrowck renumbers them into fresh region variables and relates them itself.
+    /// If outlives is a reference-typed place, the test reference is then assigned to a forged
+    /// local tied to that place's regions, so the borrow must live at least as long as that
+    /// place's region; the place itself is neither written nor needs to be mutable. Raw
+    /// pointers have no region, so cannot be tied to one.
+    fn test_borrow(
+        &mut self,
+        kind: ForgedBorrowKind,
+        p: Place<'tcx>,
+        outlives: Option<Place<'tcx>>,
+    ) -> Vec<Statement<'tcx>> {
         /// test_local nas no projections, so we take the Ty field of p' PlaceTy for it's type
         let base_ty = p.ty(&self.body.local_decls, self.tcx).ty;
-        // FIXME: do these regions need to be the same? What is Rvalue::ref expecting?
-        let test_borrow_region = self.fresh_region();
-        let test_assigned_region = self.fresh_region();
-        let test_ty = if mutable {
-            Ty::new_mut_ref(self.tcx, test_assigned_region, base_ty)
-        } else {
-            Ty::new_imm_ref(self.tcx, test_assigned_region, base_ty)
-        };
+        let erased = self.tcx.lifetimes.re_erased;
+        let test_ty = kind.ty(self.tcx, erased, base_ty);
         let test_local = self.allocate_fresh_local(test_ty);
         let test_place = self.local_to_place(test_local);
-        let test_borrow_kind = if mutable {
-            BorrowKind::Mut {kind: MutBorrowKind::Default}
-        } else {
-            BorrowKind::Shallow
-        };
-        return vec![
+        let mut statements = vec![
             StatementKind::StorageLive(test_local),
-            StatementKind::Assign(Box::new((test_place, Rvalue::Ref(test_borrow_region, test_borrow_kind, p)))),
-            StatementKind::StorageDead(test_local),
+            StatementKind::Assign(Box::new((test_place, kind.rvalue(erased, p)))),
+        ];
+        if let Some(target) = outlives {
+            let target_ty = target.ty(&self.body.local_decls, self.tcx).ty;
+            assert!(
+                !kind.is_raw()
+                    && matches!(target_ty.kind(), ty::Ref(_, pointee, m) if *pointee == base_ty && *m == kind.mutability()),
+                "{:?} cannot hold the test borrow of {:?}",
+                target,
+                p
+            );
+            let test_operand = match kind.mutability() {
+                Mutability::Mut => Operand::Move(test_place),
+                Mutability::Not => Operand::Copy(test_place),
+            };
+            let (tie_local, tie_statements) = self.tie_regions(target);
+            statements.extend(tie_statements);
+            statements.push(StatementKind::Assign(Box::new((
+                self.local_to_place(tie_local),
+                Rvalue::Use(test_operand),
+            ))));
+            statements.push(StatementKind::StorageDead(tie_local));
+        }
+        statements.push(StatementKind::StorageDead(test_local));
+        statements
+            .into_iter()
+            .map(|kind| Statement {
+                source_info: FORGED_SOURCE_INFO,
+                kind,
+            })
+            .collect::<_>()
+    }
+
+
+    /// Statements which give a fresh forged local the type of a place, with the same regions
+    /// Both are ascribed, invariantly, the place's type with a fresh region variable in place
+    /// of each of its regions, as a `let` with a type annotation would be. Unlike an assignment
+    /// to the place, an ascription neither writes it nor needs it to be mutable or initialised.
+    /// Returns the forged local, whose storage the statements make live.
+    fn tie_regions(&mut self, place: Place<'tcx>) -> (Local, Vec<StatementKind<'tcx>>) {
+        let ty = place.ty(&self.body.local_decls, self.tcx).ty;
+        let infcx = self.tcx.infer_ctxt().build();
+        let user_ty = self.tcx.fold_regions(ty, |_, _| {
+            infcx.next_region_var(RegionVariableOrigin::MiscVariable(DUMMY_SP))
+        });
+        let annotation = self
+            .body
+            .user_type_annotations
+            .push(CanonicalUserTypeAnnotation {
+                user_ty: Box::new(infcx.canonicalize_user_type_annotation(UserType::Ty(user_ty))),
+                span: DUMMY_SP,
+                inferred_ty: ty,
+            });
+        let ascribe = |place: Place<'tcx>| {
+            StatementKind::AscribeUserType(
+                Box::new((
+                    place,
+                    UserTypeProjection {
+                        base: annotation,
+                        projs: vec![],
+                    },
+                )),
+                ty::Variance::Invariant,
+            )
+        };
+
+        let tie_local = self.allocate_fresh_local(ty);
+        let tie_place = self.local_to_place(tie_local);
+        let statements = vec![
+            StatementKind::StorageLive(tie_local),
+            ascribe(place),
+            ascribe(tie_place),
+        ];
+        (tie_local, statements)
+    }
+
+    /// Generates the test code to make the region of longer outlive the region of shorter
+    /// Both places are references to the same type, and (*longer) is reborrowed into a forged
+    /// local tied to the regions of shorter, which is left as it is. Borrowck then accepts the
+    /// test only if longer's region can outlive shorter's here, and if the reborrow is allowed
+    /// at this point.
+    pub fn test_outlives(
+        &mut self,
+        longer: Place<'tcx>,
+        shorter: Place<'tcx>,
+    ) -> Vec<Statement<'tcx>> {
+        let shorter_ty = shorter.ty(&self.body.local_decls, self.tcx).ty;
+        let ty::Ref(_, _, mutability) = shorter_ty.kind() else {
+            panic!("{:?} is not a reference", shorter);
+        };
+        let reborrow_kind = match mutability {
+            Mutability::Mut => ForgedBorrowKind::Mut,
+            Mutability::Not => ForgedBorrowKind::Shared,
+        };
+        let reborrowed = self.tcx.mk_place_deref(longer);
+        self.test_borrow(reborrow_kind, reborrowed, Some(shorter))
+    }
+
+
+    /// Unwind action of a forged call or drop, and the cleanup block it unwinds to if any
+    fn unwind_action(&mut self, unwind: ForgedUnwind) -> (UnwindAction, Option<BasicBlock>) {
+        match unwind {
+            ForgedUnwind::Unreachable => (UnwindAction::Unreachable, None),
+            ForgedUnwind::Terminate => (UnwindAction::Terminate(UnwindTerminateReason::Abi), None),
+            ForgedUnwind::Continue => (UnwindAction::Continue, None),
+            ForgedUnwind::Cleanup => {
+                let cleanup_block = self.allocate_cleanup_block();
+                (UnwindAction::Cleanup(cleanup_block), Some(cleanup_block))
+            }
+        }
+    }
+
+    /// Statements which only mark their block as forged
+    /// Tests whose code mentions nothing but the tested place need these, so that errors
+    /// in their block are still attributed to them.
+    fn marker_statements(&mut self) -> Vec<Statement<'tcx>> {
+        let marker = self.allocate_fresh_local(self.tcx.types.unit);
+        vec![
+            StatementKind::StorageLive(marker),
+            StatementKind::StorageDead(marker),
         ]
         .into_iter()
         .map(|kind| Statement {
             source_info: FORGED_SOURCE_INFO,
             kind,
         })
-        .collect::<_>();
+        .collect::<_>()
     }
 
+    /// Generates the test code to end the storage of a local
+    /// Borrowck rejects it if a live borrow points into the local.
+    pub fn test_storage_dead(&mut self, p: Place<'tcx>) -> Vec<Statement<'tcx>> {
+        let local = p.as_local().expect("only a local's storage can be ended");
+        let mut statements = self.marker_statements();
+        statements.push(Statement {
+            source_info: FORGED_SOURCE_INFO,
+            kind: StatementKind::StorageDead(local),
+        });
+        statements
+    }
 
-    /// Generate a list of statements to test a move-in statement
-    /// This also generates a new basic block, to jump to after the call.
-    fn test_move_in(&mut self, block: BasicBlock, p: Place<'tcx>) {
-        let base_ty = p.ty(&self.body.local_decls, self.tcx).ty;
+    /// Generates the test code to drop a place, at the end of a block
+    /// The block's terminator moves to a continuation, which the Drop returns to: Unreachable
+    /// for a SplitKind::Test block, otherwise the jump back into the body.
+    /// Returns the cleanup block the drop unwinds to, if one was asked for.
+    fn test_drop(
+        &mut self,
+        block: BasicBlock,
+        p: Place<'tcx>,
+        unwind: ForgedUnwind,
+    ) -> Option<BasicBlock> {
+        let marker_statements = self.marker_statements();
+        self.get_data_mut(block).statements.extend(marker_statements);
 
         let kont_block = self.allocate_block();
-        let kont_terminator = Terminator {
-            kind: TerminatorKind::Return,
+        let kont_terminator = self.get_data_mut(block).terminator.take();
+        self.set_terminator(kont_block, kont_terminator);
+
+        let (unwind_action, cleanup_block) = self.unwind_action(unwind);
+        let drop_terminator = Terminator {
             source_info: FORGED_SOURCE_INFO,
+            kind: TerminatorKind::Drop {
+                place: p,
+                target: kont_block,
+                unwind: unwind_action,
+                replace: false,
+            },
         };
-        self.set_statements(kont_block, vec![]);
-        self.set_terminator(kont_block, Some(kont_terminator));
+        self.set_terminator(block, Some(drop_terminator));
+        cleanup_block
+    }
+
+    /// Generates the test code to move a fresh value into a place, at the end of a block
+    /// The havoc helper produces the value in a fresh local, which is then moved into p.
+    /// Statements already in the block are kept, and its terminator runs after the move-in:
+    /// Unreachable for a SplitKind::Test block, otherwise the jump to the continuation.
+    /// Returns the cleanup block the call unwinds to, if one was asked for.
+    fn test_move_in(
+        &mut self,
+        block: BasicBlock,
+        p: Place<'tcx>,
+        unwind: ForgedUnwind,
+    ) -> Option<BasicBlock> {
+        let base_ty = p.ty(&self.body.local_decls, self.tcx).ty;
+        let test_local = self.allocate_fresh_local(base_ty);
+        let test_place = self.local_to_place(test_local);
 
-        // FIXME: Requires testing. Will the typechecker complan that we are assigning to a nonexistent defID
-        let call_ty = Ty::new_fn_def(self.tcx, self.forge_def_id(), [base_ty]);
+        let kont_block = self.allocate_block();
+        let kont_terminator = self.get_data_mut(block).terminator.take();
+        let kont_statements = vec![
+            StatementKind::Assign(Box::new((p, Rvalue::Use(Operand::Move(test_place))))),
+            StatementKind::StorageDead(test_local),
+        ]
+        .into_iter()
+        .map(|kind| Statement {
+            source_info: FORGED_SOURCE_INFO,
+            kind,
+        })
+        .collect::<_>();
+        self.set_statements(kont_block, kont_statements);
+        self.set_terminator(kont_block, kont_terminator);
+
+        let (unwind_action, cleanup_block) = self.unwind_action(unwind);
+        let call_terminator = self.havoc_call(base_ty, test_place, kont_block, unwind_action);
+        self.get_data_mut(block).statements.push(Statement {
+            source_info: FORGED_SOURCE_INFO,
+            kind: StatementKind::StorageLive(test_local),
+        });
+        self.set_terminator(block, Some(call_terminator));
+        cleanup_block
+    }
+
+    /// A call of the havoc helper which writes a fresh value of type ty to destination
+    fn havoc_call(
+        &mut self,
+        ty: Ty<'tcx>,
+        destination: Place<'tcx>,
+        target: BasicBlock,
+        unwind: UnwindAction,
+    ) -> Terminator<'tcx> {
+        // analyzer_havoc::<ty>() has type ty, so the call typechecks against the destination
+        let call_ty = Ty::new_fn_def(self.tcx, self.havoc_def_id(), [ty]);
         let call_terminator_kind = TerminatorKind::Call{
             func: Operand::Constant(Box::new(Constant {
                 span: DUMMY_SP,
@@ -301,18 +761,67 @@ impl<'mir, 'tcx: 'mir> BodyModifier<'mir, 'tcx> {
                 literal: ConstantKind::Val(ConstValue::ZeroSized, call_ty)
             })),
             args: vec![],
-            destination: p,
-            target: Some(kont_block),
-            unwind: UnwindAction::Terminate(UnwindTerminateReason::Abi),
+            destination,
+            target: Some(target),
+            unwind,
             call_source: CallSource::Normal,
             fn_span: DUMMY_SP,
         };
-        let call_terminator = Terminator {
+        Terminator {
             kind: call_terminator_kind,
             source_info: FORGED_SOURCE_INFO,
-        };
-        self.set_statements(block, vec![]);
+        }
+    }
+
+    /// Generates the test code to overwrite a place with a fresh value, at the end of a block
+    /// The havoc helper's call writes to p itself. Borrowck checks a call's destination as a
+    /// deep write, so unlike the assignment of test_move_in, the test also conflicts with
+    /// loans of places reached through p. The old value is not dropped first, as with
+    /// ptr::write. The call mentions nothing forged by this test alone, so marker statements
+    /// precede it. Returns the cleanup block the call unwinds to, if one was asked for.
+    fn test_write(
+        &mut self,
+        block: BasicBlock,
+        p: Place<'tcx>,
+        unwind: ForgedUnwind,
+    ) -> Option<BasicBlock> {
+        let base_ty = p.ty(&self.body.local_decls, self.tcx).ty;
+        let markers = self.marker_statements();
+        self.get_data_mut(block).statements.extend(markers);
+
+        let kont_block = self.allocate_block();
+        let kont_terminator = self.get_data_mut(block).terminator.take();
+        self.set_terminator(kont_block, kont_terminator);
+
+        let (unwind_action, cleanup_block) = self.unwind_action(unwind);
+        let call_terminator = self.havoc_call(base_ty, p, kont_block, unwind_action);
         self.set_terminator(block, Some(call_terminator));
+        cleanup_block
+    }
+
+    /// Generates the test code to read a place
+    /// Copy places are copied into a fresh local. Other places are shallow borrowed, which
+    /// borrowck checks as a shallow read; a FakeRead would only check that p is initialised,
+    /// and mentions no forged local by which to attribute its errors to the test.
+    pub fn test_read(&mut self, p: Place<'tcx>) -> Vec<Statement<'tcx>> {
+        let base_ty = p.ty(&self.body.local_decls, self.tcx).ty;
+        let param_env = self.tcx.param_env(self.body.source.def_id());
+        if !base_ty.is_copy_modulo_regions(self.tcx, param_env) {
+            return self.test_borrow(ForgedBorrowKind::Shallow, p, None);
+        }
+        let test_local = self.allocate_fresh_local(base_ty);
+        let test_place = self.local_to_place(test_local);
+        vec![
+            StatementKind::StorageLive(test_local),
+            StatementKind::Assign(Box::new((test_place, Rvalue::Use(Operand::Copy(p))))),
+            StatementKind::StorageDead(test_local),
+        ]
+        .into_iter()
+        .map(|kind| Statement {
+            source_info: FORGED_SOURCE_INFO,
+            kind,
+        })
+        .collect::<_>()
     }
 
     /// Example: turns a statement into a nop
@@ -352,6 +861,20 @@ impl<'mir, 'tcx: 'mir> BodyModifier<'mir, 'tcx> {
         return block;
     }
 
+    /// Allocates a cleanup block which resumes unwinding
+    fn allocate_cleanup_block(&mut self) -> BasicBlock {
+        let block = self.allocate_block();
+        self.get_data_mut(block).is_cleanup = true;
+        self.set_terminator(
+            block,
+            Some(Terminator {
+                source_info: FORGED_SOURCE_INFO,
+                kind: TerminatorKind::UnwindResume,
+            }),
+        );
+        return block;
+    }
+
     fn set_terminator(&mut self, block: BasicBlock, terminator: Option<Terminator<'tcx>>) {
         self.body.basic_blocks.as_mut()[block].terminator = terminator;
     }
@@ -424,9 +947,510 @@ impl<'mir, 'tcx: 'mir> BodyModifier<'mir, 'tcx> {
         // looking up loc should return (Location { kont_block, 0 }).
         self.redirect_indices_starting_at_to_block(current_loc, kont_block);
 
+        self.splits.push(Split {
+            block: current_block,
+            kont_block,
+            test_block,
+        });
+
         // Return the index of the test block, to be populated by another function
         return test_block;
     }
+
+    /// Splits before an original location and fills the unreachable test block with a test
+    /// id identifies the test among those planned for the body, and live holds the locals
+    /// live at loc in the unmodified MIR, which the test block keeps live.
+    pub fn insert_test(
+        &mut self,
+        id: usize,
+        loc: &Location,
+        kind: TestKind,
+        p: Place<'tcx>,
+        outlives: Option<Place<'tcx>>,
+        live: &BitSet<Local>,
+    ) -> verdicts::TestRecord {
+        assert_ne!(
+            kind,
+            TestKind::TwoPhaseActivation,
+            "two-phase activation tests are inserted by insert_two_phase_test"
+        );
+        let local_count = self.body.local_decls.len();
+
+        let test_block = self.allocate_split_branch_before(loc, SplitKind::Test);
+        match kind {
+            TestKind::MoveOut => {
+                let statements = self.test_move_out(p);
+                self.set_statements(test_block, statements);
+            }
+            TestKind::SharedBorrow
+            | TestKind::ShallowBorrow
+            | TestKind::MutBorrow
+            | TestKind::TwoPhaseBorrow
+            | TestKind::ClosureCaptureBorrow
+            | TestKind::RawConst
+            | TestKind::RawMut => {
+                let borrow_kind = kind.borrow_kind().unwrap();
+                let statements = self.test_borrow(borrow_kind, p, outlives);
+                self.set_statements(test_block, statements);
+            }
+            TestKind::Outlives => {
+                let shorter = outlives.expect("an outlives test needs a place to outlive");
+                let statements = self.test_outlives(p, shorter);
+                self.set_statements(test_block, statements);
+            }
+            TestKind::MoveIn => {
+                self.test_move_in(test_block, p, self.forged_unwind);
+            }
+            TestKind::Write => {
+                self.test_write(test_block, p, self.forged_unwind);
+            }
+            TestKind::Read => {
+                let statements = self.test_read(p);
+                self.set_statements(test_block, statements);
+            }
+            TestKind::StorageDead => {
+                let statements = self.test_storage_dead(p);
+                self.set_statements(test_block, statements);
+            }
+            TestKind::Drop => {
+                self.test_drop(test_block, p, self.forged_unwind);
+            }
+            TestKind::TwoPhaseActivation => unreachable!(),
+        }
+        self.keep_live(test_block, live);
+
+        verdicts::TestRecord {
+            id,
+            kind,
+            location: *loc,
+            activation: None,
+            place: format!("{:?}", p),
+            outlives: outlives.map(|target| format!("{:?}", target)),
+            forged: self.forged_items_since(local_count),
+            uninitialised: false,
+            live_references: vec![],
+        }
+    }
+
+    /// Uses every local of live just before the Unreachable a test ends in
+    /// A test block is a dead end, so nothing the real path goes on to use would be live in
+    /// it otherwise, and no loan still needed there could conflict with the test. The uses are
+    /// place mentions rather than fake reads: liveness counts both, but borrowck would also
+    /// check that fake read locals are initialised, which live locals need not be everywhere.
+    /// A block a test's call or drop returns to is marked as forged too, as the uses only
+    /// mention real locals.
+    fn keep_live(&mut self, test_block: BasicBlock, live: &BitSet<Local>) {
+        // Calls and drops of a test return to a block of their own, which ends the test
+        let mut block = test_block;
+        loop {
+            match &self.body.basic_blocks[block].terminator().kind {
+                TerminatorKind::Unreachable => break,
+                TerminatorKind::Call {
+                    target: Some(target),
+                    ..
+                }
+                | TerminatorKind::Drop { target, .. } => block = *target,
+                kind => panic!("test block {:?} ends in {:?}", test_block, kind),
+            }
+        }
+        if block != test_block {
+            let marker_statements = self.marker_statements();
+            self.get_data_mut(block).statements.extend(marker_statements);
+        }
+        let uses: Vec<_> = live
+            .iter()
+            .map(|local| Statement {
+                source_info: FORGED_SOURCE_INFO,
+                kind: StatementKind::PlaceMention(Box::new(self.local_to_place(local))),
+            })
+            .collect();
+        self.get_data_mut(block).statements.extend(uses);
+    }
+
+    /// Records a test without inserting it, as its place is uninitialised on some path to it
+    pub fn skip_uninitialised_test(
+        &mut self,
+        id: usize,
+        spec: &config::TestSpec,
+        p: Place<'tcx>,
+        outlives: Option<Place<'tcx>>,
+    ) -> verdicts::TestRecord {
+        verdicts::TestRecord {
+            id,
+            kind: spec.kind,
+            location: spec.location,
+            activation: spec.activation,
+            place: format!("{:?}", p),
+            outlives: outlives.map(|target| format!("{:?}", target)),
+            forged: Default::default(),
+            uninitialised: true,
+            live_references: vec![],
+        }
+    }
+
+    /// Inserts a test which reserves a two-phase mutable borrow of p before one original
+    /// location and activates it before another
+    /// The reservation is ghost code on the real path, and the activation is a test block at
+    /// activate_loc, so borrowck checks every access between the two against the reservation.
+    /// reserve_loc must dominate activate_loc, or the borrow may be activated uninitialised.
+    /// live holds the locals live at activate_loc in the unmodified MIR.
+    pub fn insert_two_phase_test(
+        &mut self,
+        id: usize,
+        reserve_loc: &Location,
+        activate_loc: &Location,
+        p: Place<'tcx>,
+        live: &BitSet<Local>,
+    ) -> verdicts::TestRecord {
+        let local_count = self.body.local_decls.len();
+
+        let base_ty = p.ty(&self.body.local_decls, self.tcx).ty;
+        let erased = self.tcx.lifetimes.re_erased;
+        let borrow_ty = ForgedBorrowKind::TwoPhaseMut.ty(self.tcx, erased, base_ty);
+        let borrow_local = self.allocate_ghost_local(borrow_ty);
+        let borrow_place = self.local_to_place(borrow_local);
+        let activation_local = self.allocate_fresh_local(borrow_ty);
+        let activation_place = self.local_to_place(activation_local);
+
+        self.insert_ghost_statements(
+            reserve_loc,
+            vec![
+                StatementKind::StorageLive(borrow_local),
+                StatementKind::Assign(Box::new((
+                    borrow_place,
+                    ForgedBorrowKind::TwoPhaseMut.rvalue(erased, p),
+                ))),
+            ],
+        );
+
+        // The first use of the borrow's local activates it
+        let activate_block = self.allocate_split_branch_before(activate_loc, SplitKind::Test);
+        let activate_statements = vec![
+            StatementKind::StorageLive(activation_local),
+            StatementKind::Assign(Box::new((
+                activation_place,
+                Rvalue::Use(Operand::Move(borrow_place)),
+            ))),
+            StatementKind::StorageDead(activation_local),
+            StatementKind::StorageDead(borrow_local),
+        ]
+        .into_iter()
+        .map(|kind| Statement {
+            source_info: FORGED_SOURCE_INFO,
+            kind,
+        })
+        .collect::<_>();
+        self.set_statements(activate_block, activate_statements);
+        self.keep_live(activate_block, live);
+
+        verdicts::TestRecord {
+            id,
+            kind: TestKind::TwoPhaseActivation,
+            location: *reserve_loc,
+            activation: Some(*activate_loc),
+            place: format!("{:?}", p),
+            outlives: None,
+            forged: self.forged_items_since(local_count),
+            uninitialised: false,
+            live_references: vec![],
+        }
+    }
+
+    /// Splits before an original location with an approximator which overwrites each place
+    /// with a fresh value
+    /// Borrowck then also considers a path on which the places were assigned anything at all:
+    /// it checks that they may be overwritten here, and that the continuation copes with them
+    /// holding loans and values unrelated to the ones they had.
+    pub fn approximate_havoc(&mut self, loc: &Location, places: &[Place<'tcx>]) {
+        let mut block = self.allocate_split_branch_before(loc, SplitKind::Approximator);
+        for p in places.iter() {
+            self.test_move_in(block, *p, self.forged_unwind);
+            // The move-in happens in the call's target, which also jumps on to the continuation
+            block = match &self.body.basic_blocks[block].terminator().kind {
+                TerminatorKind::Call {
+                    target: Some(target),
+                    ..
+                } => *target,
+                _ => unreachable!("a move-in ends its block in a call"),
+            };
+        }
+    }
+
+    /// Splits before an original location with an approximator which moves each reference
+    /// into a fresh sink local, whose storage ends at once
+    /// On that path the loans the references held are forgotten: nothing live holds them
+    /// through the references any more, and the references are uninitialised.
+    pub fn approximate_forget(&mut self, loc: &Location, references: &[Place<'tcx>]) {
+        let approximator_block = self.allocate_split_branch_before(loc, SplitKind::Approximator);
+        let mut statements = vec![];
+        for reference in references.iter() {
+            let reference_ty = reference.ty(&self.body.local_decls, self.tcx).ty;
+            assert!(reference_ty.is_ref(), "{:?} is not a reference", reference);
+            let sink = self.allocate_fresh_local(reference_ty);
+            statements.extend([
+                StatementKind::StorageLive(sink),
+                StatementKind::Assign(Box::new((
+                    self.local_to_place(sink),
+                    Rvalue::Use(Operand::Move(*reference)),
+                ))),
+                StatementKind::StorageDead(sink),
+            ]);
+        }
+        let statements = statements
+            .into_iter()
+            .map(|kind| Statement {
+                source_info: FORGED_SOURCE_INFO,
+                kind,
+            })
+            .collect::<_>();
+        self.set_statements(approximator_block, statements);
+    }
+
+    /// Splits before an original location with an approximator on which the loan taken at
+    /// an original borrow statement has expired
+    /// The local the borrow was assigned to is overwritten with a fresh reference, so the
+    /// loan no longer reaches the continuation through it along that path. Copies and
+    /// reborrows already taken from the local keep the loan alive.
+    pub fn approximate_expired_loan(&mut self, loc: &Location, borrow_loc: &Location) {
+        let current_loc = self.location_table[borrow_loc];
+        let statement = &self.body.basic_blocks[current_loc.block].statements
+            [current_loc.statement_index];
+        let holder = match &statement.kind {
+            StatementKind::Assign(box (holder, Rvalue::Ref(..))) if holder.as_local().is_some() => {
+                *holder
+            }
+            _ => panic!(
+                "{:?} does not assign a borrow to a local: {:?}",
+                borrow_loc, statement
+            ),
+        };
+        self.approximate_havoc(loc, &[holder]);
+    }
+
+    /// Could a test of p change what borrowck sees at the other tests of the body?
+    /// Test blocks are dead ends, but a test still uses p, so p is live wherever the test
+    /// can be reached from. The other locals a test block uses are live at the test anyway,
+    /// but if p can hold loans and is not, they are kept alive for longer. A test tying its
+    /// borrow to a place constrains that place's region too. live holds the locals live at
+    /// the test in the unmodified MIR.
+    pub fn may_interfere(
+        &self,
+        p: Place<'tcx>,
+        outlives: Option<Place<'tcx>>,
+        live: &BitSet<Local>,
+    ) -> bool {
+        let holds_loans = self.body.local_decls[p.local]
+            .ty
+            .walk()
+            .any(|arg| matches!(arg.unpack(), ty::GenericArgKind::Lifetime(_)));
+        outlives.is_some() || (holds_loans && !live.contains(p.local))
+    }
+
+    /// Locals forged since the given count
+    /// DefIds are left out: the havoc helper is shared by every test and approximator calling
+    /// it, so only identifies the first of them to be inserted.
+    fn forged_items_since(&self, local_count: usize) -> cleanup::ForgedItems {
+        let locals: FxHashSet<Local> = (local_count..self.body.local_decls.len())
+            .map(Local::from_usize)
+            .collect();
+        cleanup::ForgedItems {
+            ghost_locals: locals.intersection(&self.ghost.locals).cloned().collect(),
+            locals,
+            def_ids: Default::default(),
+            original_local_tys: vec![],
+            original_control_flow: Default::default(),
+        }
+    }
+
+    /// Locals which were allocated by the modifier rather than present in the original MIR
+    pub fn forged_locals(&self) -> impl Iterator<Item = Local> {
+        (self.original_local_count..self.body.local_decls.len()).map(Local::from_usize)
+    }
+
+    /// Bookkeeping needed to strip the forged code once borrowck is done with the body, and to
+    /// check the result against the unmodified body
+    pub fn forged_items(&self, original: &Body<'tcx>) -> cleanup::ForgedItems {
+        cleanup::ForgedItems {
+            locals: self.forged_locals().collect(),
+            ghost_locals: self.ghost.locals.clone(),
+            def_ids: self.forged_def_ids.iter().cloned().collect(),
+            original_local_tys: self
+                .body
+                .local_decls
+                .iter()
+                .take(self.original_local_count)
+                .map(|decl| format!("{:?}", decl.ty))
+                .collect(),
+            original_control_flow: cleanup::ControlFlow::of(self.tcx, original),
+        }
+    }
+
+    /// Copy of the current body with every split undone and all forged blocks and locals removed
+    pub fn restored_body(&self) -> Body<'tcx> {
+        let mut body = self.body.clone();
+        let blocks = body.basic_blocks.as_mut();
+
+        // Undo the splits in reverse, so that a continuation which was itself split
+        //  is whole again by the time it is merged back
+        for split in self.splits.iter().rev() {
+            let kont_data = mem::replace(&mut blocks[split.kont_block], BasicBlockData::new(None));
+            let block_data = &mut blocks[split.block];
+            block_data.statements.extend(kont_data.statements);
+            block_data.terminator = kont_data.terminator;
+        }
+
+        // All forged blocks and locals were pushed after the original ones
+        blocks.raw.truncate(self.original_block_count);
+        body.local_decls.raw.truncate(self.original_local_count);
+        body
+    }
+
+    /// Checks that undoing all modifications gives back the original body
+    pub fn restores_to(&self, original: &Body<'tcx>) -> bool {
+        let restored = self.restored_body();
+        (format!("{:?}", restored.basic_blocks) == format!("{:?}", original.basic_blocks))
+            && (format!("{:?}", restored.local_decls) == format!("{:?}", original.local_decls))
+    }
+}
+
+/// A test planned for a body, with its places resolved in the unmodified MIR
+struct PlannedTest<'a, 'tcx> {
+    /// Index of the test among those planned for the body
+    id: usize,
+    spec: &'a config::TestSpec,
+    place: Place<'tcx>,
+    outlives: Option<Place<'tcx>>,
+    /// Set when the place is uninitialised on some path to the test, which is then not inserted
+    uninitialised: bool,
+    /// Set when the test's verdict can be told apart from those of the other tests in the body
+    shares_body: bool,
+    /// Locals live where the test runs in the unmodified MIR: at its activation for a
+    /// two-phase test, and at its location otherwise
+    live: BitSet<Local>,
+}
+
+/// Checks that code can be inserted before a location given on the command line
+fn check_location(body: &Body<'_>, location: Location) -> Result<(), String> {
+    let Some(data) = body.basic_blocks.get(location.block) else {
+        return Err(format!("the body has no block {:?}", location.block));
+    };
+    if location.statement_index > data.statements.len() {
+        return Err(format!("{:?} lies past the terminator of its block", location));
+    }
+    if data.is_cleanup {
+        return Err(format!("{:?} lies in a cleanup block", location));
+    }
+    Ok(())
+}
+
+/// Resolves the places of a test in the unmodified MIR, checking that the test can be
+/// inserted as given
+fn resolve_test<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    body: &Body<'tcx>,
+    spec: &config::TestSpec,
+) -> Result<(Place<'tcx>, Option<Place<'tcx>>), String> {
+    check_location(body, spec.location)?;
+    if let Some(activation) = spec.activation {
+        check_location(body, activation)?;
+        // Otherwise the borrow could be activated on a path which never reserved it
+        if !spec
+            .location
+            .dominates(activation, body.basic_blocks.dominators())
+        {
+            return Err(format!(
+                "the reservation at {:?} does not dominate the activation at {:?}",
+                spec.location, activation
+            ));
+        }
+    }
+    let place = spec.place.to_place(tcx, &body.local_decls)?;
+    let outlives = spec
+        .outlives
+        .as_ref()
+        .map(|target| target.to_place(tcx, &body.local_decls))
+        .transpose()?;
+    // An outlives test reborrows the referent of its place into the second place
+    if let (TestKind::Outlives, Some(shorter)) = (spec.kind, outlives) {
+        let longer_ty = place.ty(&body.local_decls, tcx).ty;
+        let shorter_ty = shorter.ty(&body.local_decls, tcx).ty;
+        let reborrows = match (longer_ty.kind(), shorter_ty.kind()) {
+            (
+                ty::Ref(_, longer_pointee, longer_mutability),
+                ty::Ref(_, shorter_pointee, shorter_mutability),
+            ) => {
+                longer_pointee == shorter_pointee
+                    && (*shorter_mutability == Mutability::Not
+                        || *longer_mutability == Mutability::Mut)
+            }
+            _ => false,
+        };
+        if !reborrows {
+            return Err(format!(
+                "{:?} of type {} cannot be reborrowed into {:?} of type {}",
+                place, longer_ty, shorter, shorter_ty
+            ));
+        }
+    }
+    // The test reference is assigned to a local with the regions of the place it must outlive
+    if let (Some(borrow_kind), Some(target)) = (spec.kind.borrow_kind(), outlives) {
+        let pointee = place.ty(&body.local_decls, tcx).ty;
+        let target_ty = target.ty(&body.local_decls, tcx).ty;
+        let holds_borrow = !borrow_kind.is_raw()
+            && matches!(target_ty.kind(), ty::Ref(_, target_pointee, mutability)
+                if *target_pointee == pointee && *mutability == borrow_kind.mutability());
+        if !holds_borrow {
+            return Err(format!(
+                "{:?} of type {} cannot hold a {} of {:?} of type {}",
+                target, target_ty, spec.kind, place, pointee
+            ));
+        }
+    }
+    Ok((place, outlives))
+}
+
+/// Resolves the places an approximator havocs or forgets in the unmodified MIR, checking that
+/// the approximator can be inserted as given
+fn resolve_approximator<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    body: &Body<'tcx>,
+    approximator: &config::ApproximatorSpec,
+) -> Result<Vec<Place<'tcx>>, String> {
+    check_location(body, approximator.location)?;
+    match &approximator.template {
+        config::ApproximatorTemplate::Havoc(places) => places
+            .iter()
+            .map(|place| place.to_place(tcx, &body.local_decls))
+            .collect(),
+        config::ApproximatorTemplate::Forget(references) => references
+            .iter()
+            .map(|reference| {
+                let place = reference.to_place(tcx, &body.local_decls)?;
+                let ty = place.ty(&body.local_decls, tcx).ty;
+                if !ty.is_ref() {
+                    return Err(format!("{:?} of type {} is not a reference", place, ty));
+                }
+                Ok(place)
+            })
+            .collect(),
+        config::ApproximatorTemplate::ExpiredLoan(borrow_location) => {
+            let statement = body
+                .basic_blocks
+                .get(borrow_location.block)
+                .and_then(|data| data.statements.get(borrow_location.statement_index));
+            match statement.map(|statement| &statement.kind) {
+                Some(StatementKind::Assign(box (holder, Rvalue::Ref(..))))
+                    if holder.as_local().is_some() =>
+                {
+                    Ok(vec![])
+                }
+                _ => Err(format!(
+                    "{:?} does not assign a borrow to a local",
+                    borrow_location
+                )),
+            }
+        }
+    }
 }
 
 #[allow(clippy::needless_lifetimes)]
@@ -436,28 +1460,207 @@ fn mir_built<'tcx>(tcx: ty::TyCtxt<'tcx>, def_id: LocalDefId) -> ProvidedValue<'
     rustc_middle::middle::provide(&mut providers);
     let mir_built_ptr = rustc_interface::DEFAULT_QUERY_PROVIDERS.mir_built;
     let mut body = mir_built_ptr(tcx, def_id).steal();
-    println!("=================================");
-    println!("[log] initial MIR: {:#?}", body.basic_blocks);
 
-    // (Example) Modify and return the MIR
-    // Used with examples/reborrowing.rs
+    // In these modes this compilation only finds the tests to run, which are compiled later
+    match config::config().mode {
+        config::AnalysisMode::Capabilities => {
+            capabilities::record_body(tcx, def_id, &body);
+            return tcx.alloc_steal_mir(body);
+        }
+        config::AnalysisMode::Search => {
+            search::record_body(tcx, def_id, &body);
+            return tcx.alloc_steal_mir(body);
+        }
+        config::AnalysisMode::Tests
+        | config::AnalysisMode::Differential
+        | config::AnalysisMode::Instrument => {}
+    }
 
-    let mut body_modifier = BodyModifier::new(tcx, &mut body);
-    let tb0 = body_modifier.allocate_split_branch_before(
-        &mut (&Location {
-            block: BasicBlock::from_u32(0),
-            statement_index: 5,
-        }),
-        SplitKind::Test,
-    );
+    let function = tcx.def_path_str(def_id.to_def_id());
+    let (specs, coverage) = if config::config().mode == config::AnalysisMode::Instrument {
+        match instrument::plan(tcx, def_id, &body) {
+            Some((specs, coverage)) => (specs, Some(coverage)),
+            None => (vec![], None),
+        }
+    } else {
+        let mut specs: Vec<_> = config::config().tests_for(&function).cloned().collect();
+        let plans: Vec<_> = config::config().live_plans_for(&function).collect();
+        if !plans.is_empty() {
+            let live_locals = liveness::LiveLocals::compute(&body);
+            specs.extend(plans.iter().flat_map(|plan| live_locals.expand(&body, plan)));
+        }
+        (specs, None)
+    };
+    let approximators: Vec<_> = config::config().approximators_for(&function).collect();
+    // Bodies are rewritten for the reborrow graph even without tests, to mark their blocks
+    let has_insertions = !specs.is_empty() || !approximators.is_empty();
+    if !has_insertions && config::config().reborrow_graph_dir.is_none() {
+        return tcx.alloc_steal_mir(body);
+    }
 
-    body_modifier.test_move_in(tb0, body_modifier.local_to_place(Local::from_u32(1)));
-    // let tb0_statements =
-    //     body_modifier.test_shared_borrow(body_modifier.local_to_place(Local::from_u32(1)));
-    //     /*  body_modifier.test_move_out(body_modifier.local_to_place(Local::from_u32(1))); */
-    // body_modifier.set_statements(tb0, tb0_statements);
-    println!("first test block is {:?}", tb0);
+    let original_body = body.clone();
+    let maybe_uninitialised = (config::config().uninitialised
+        == config::UninitialisedTests::Report)
+        .then(|| init::MaybeUninitialised::compute(tcx, &original_body));
+    let live_locals = liveness::LiveLocals::compute(&original_body);
+    println!("=================================");
+    println!("[log] initial MIR of {}: {:#?}", function, body.basic_blocks);
 
+    // Modify and return the MIR
+    // e.g. --analysis-test=main@bb0[5]:_1:move-in with examples/reborrowing.rs
+    let mut body_modifier = BodyModifier::new(tcx, &mut body);
+    body_modifier.forged_unwind = config::config().unwind;
+    // Approximators which cannot be inserted as given are reported and left out
+    for approximator in approximators.iter() {
+        let places = match resolve_approximator(tcx, &original_body, approximator) {
+            Ok(places) => places,
+            Err(message) => {
+                tcx.sess
+                    .err(format!("cannot insert approximator {}: {}", approximator, message));
+                continue;
+            }
+        };
+        match &approximator.template {
+            config::ApproximatorTemplate::Havoc(_) => {
+                body_modifier.approximate_havoc(&approximator.location, &places)
+            }
+            config::ApproximatorTemplate::Forget(_) => {
+                body_modifier.approximate_forget(&approximator.location, &places)
+            }
+            config::ApproximatorTemplate::ExpiredLoan(borrow_location) => body_modifier
+                .approximate_expired_loan(&approximator.location, borrow_location),
+        }
+    }
+    // Tests which cannot be inserted as given are reported and left out; the others keep the
+    //  ids they were given in, which is how their reports are matched up with them
+    let mut planned: Vec<_> = specs
+        .iter()
+        .enumerate()
+        .filter_map(|(id, spec)| {
+            let (place, outlives) = match resolve_test(tcx, &original_body, spec) {
+                Ok(places) => places,
+                Err(message) => {
+                    tcx.sess.err(format!("cannot insert test {}: {}", spec, message));
+                    return None;
+                }
+            };
+            let uninitialised = spec.kind.needs_initialised()
+                && maybe_uninitialised
+                    .as_ref()
+                    .is_some_and(|init| init.contains(&original_body, spec.location, place));
+            let live = live_locals.at(&original_body, spec.activation.unwrap_or(spec.location));
+            Some(PlannedTest {
+                id,
+                spec,
+                place,
+                outlives,
+                uninitialised,
+                // The Polonius facts must be the engine's and express every error the test
+                //  can cause at its location, and the test must leave what borrowck sees
+                //  elsewhere alone
+                shares_body: config::config().engine != config::BorrowckEngine::Nll
+                    && spec.kind.facts_coverage() != FactsCoverage::Partial
+                    && !body_modifier.may_interfere(place, outlives, &live),
+                live,
+            })
+        })
+        .collect();
+    // Tests which cannot share the body are compiled on their own once this compilation is
+    //  done, unless the body would hold nothing else
+    let inserted_count = planned.iter().filter(|test| !test.uninitialised).count();
+    let mut isolated = vec![];
+    if inserted_count > 1 {
+        let mut keep_first = planned
+            .iter()
+            .all(|test| test.uninitialised || !test.shares_body);
+        planned.retain(|test| {
+            if test.uninitialised || test.shares_body {
+                return true;
+            }
+            if keep_first {
+                keep_first = false;
+                return true;
+            }
+            isolated.push(verdicts::IsolatedTest {
+                function: function.clone(),
+                id: test.id,
+                spec: test.spec.clone(),
+            });
+            false
+        });
+    }
+    // Place errors are the same wherever a test is, so one test of each place and kind packed
+    //  with others is enough to find them, once the facts have found a location to run it at
+    let mut place_checks: Vec<verdicts::PlaceCheck> = vec![];
+    if planned.iter().filter(|test| !test.uninitialised).count() > 1 {
+        for test in planned.iter().filter(|test| !test.uninitialised) {
+            if test.spec.kind.facts_coverage() != FactsCoverage::AllButPlaceErrors {
+                continue;
+            }
+            let key = (test.spec.kind, test.spec.place.to_string());
+            match place_checks
+                .iter_mut()
+                .find(|check| (check.spec.kind, check.spec.place.to_string()) == key)
+            {
+                Some(check) => check.tests.push((test.id, test.spec.location)),
+                None => place_checks.push(verdicts::PlaceCheck {
+                    function: function.clone(),
+                    spec: test.spec.clone(),
+                    tests: vec![(test.id, test.spec.location)],
+                }),
+            }
+        }
+    }
+    let tests: Vec<_> = planned
+        .iter()
+        .map(|test| {
+            let live_references = live_locals.live_references(&original_body, test.spec.location);
+            let mut record = if test.uninitialised {
+                body_modifier.skip_uninitialised_test(test.id, test.spec, test.place, test.outlives)
+            } else {
+                match &test.spec.activation {
+                    Some(activation) => body_modifier.insert_two_phase_test(
+                        test.id,
+                        &test.spec.location,
+                        activation,
+                        test.place,
+                        &test.live,
+                    ),
+                    None => body_modifier.insert_test(
+                        test.id,
+                        &test.spec.location,
+                        test.spec.kind,
+                        test.place,
+                        test.outlives,
+                        &test.live,
+                    ),
+                }
+            };
+            record.live_references = live_references;
+            record
+        })
+        .collect();
+
+    let block_markers = explain::mark_original_blocks(&mut body_modifier, &original_body);
+    // Ghost code which touches real state would change the verdicts, so none are given
+    if let Err(violation) = body_modifier.verify_ghost_code() {
+        tcx.sess
+            .err(format!("bad ghost code in {}, left unmodified: {}", function, violation));
+        return tcx.alloc_steal_mir(original_body);
+    }
+    // Every forged block and local must be removable again before the body is lowered further
+    assert!(
+        body_modifier.restores_to(&original_body),
+        "forged code in {:?} cannot be stripped",
+        def_id
+    );
+    cleanup::register_forged_items(def_id, body_modifier.forged_items(&original_body));
+    explain::register_block_markers(def_id, block_markers);
+    if has_insertions {
+        verdicts::register_isolated_tests(isolated);
+        verdicts::register_place_checks(place_checks);
+        verdicts::register_tests(def_id, function, tests, coverage);
+    }
 
     // let tb1 = body_modifier.allocate_split_branch_before(
     //     &mut (&Location {
@@ -507,9 +1710,9 @@ fn override_queries(
     _external: &mut rustc_middle::query::ExternProviders,
 ) {
     // https://doc.rust-lang.org/stable/nightly-rustc/rustc_middle/query/struct.Providers.html
-    // todo!("implement the override");
-    // local.mir_borrowck = mir_borrowck;
     local.mir_built = mir_built;
+    local.mir_borrowck = verdicts::mir_borrowck;
+    local.mir_drops_elaborated_and_const_checked = cleanup::mir_drops_elaborated_and_const_checked;
 }
 
 impl rustc_driver::Callbacks for OurCompilerCalls {
@@ -518,6 +1721,31 @@ impl rustc_driver::Callbacks for OurCompilerCalls {
         config.override_queries = Some(override_queries);
     }
 
+    fn after_parsing<'tcx>(
+        &mut self,
+        compiler: &rustc_interface::interface::Compiler,
+        queries: &'tcx rustc_interface::Queries<'tcx>,
+    ) -> Compilation {
+        // Move-in tests call the havoc helper, which must be part of the crate
+        havoc::inject_havoc(
+            &compiler.session().parse_sess,
+            queries.parse().unwrap().get_mut(),
+        );
+        Compilation::Continue
+    }
+
+    fn after_expansion<'tcx>(
+        &mut self,
+        _compiler: &rustc_interface::interface::Compiler,
+        queries: &'tcx rustc_interface::Queries<'tcx>,
+    ) -> Compilation {
+        // The report is taken once the compiler returns, when there is no tcx left to ask
+        queries.global_ctxt().unwrap().enter(|tcx| {
+            self.crate_name = Some(tcx.crate_name(LOCAL_CRATE).to_string());
+        });
+        Compilation::Continue
+    }
+
     fn after_analysis<'tcx>(
         &mut self,
          compiler: &rustc_interface::interface::Compiler,
@@ -552,30 +1780,163 @@ impl rustc_driver::Callbacks for OurCompilerCalls {
     }
 }
 
+/// Environment variable through which `cargo mir-rewrite` forwards `--analysis-*` options,
+/// separated by ASCII unit separators like CARGO_ENCODED_RUSTFLAGS
+const ANALYSIS_ARGS_ENV: &str = "MIR_REWRITE_ANALYSIS_ARGS";
+
+/// Environment variable naming a file of `--analysis-*` options, one per line, through which
+/// child compilations get theirs: a batch of tests can be too long for a command line
+const ANALYSIS_ARGS_FILE_ENV: &str = "MIR_REWRITE_ANALYSIS_ARGS_FILE";
+
+/// Does this rustc invocation compile a crate we should analyse?
+/// Version queries, `--print` requests and build scripts go straight to rustc.
+fn is_analysed_crate(rustc_args: &[String]) -> bool {
+    let has_input = rustc_args.iter().any(|arg| arg.ends_with(".rs"));
+    let is_query = rustc_args
+        .iter()
+        .any(|arg| arg == "-vV" || arg == "--version" || arg.starts_with("--print"));
+    let is_build_script = rustc_args
+        .windows(2)
+        .any(|pair| pair[0] == "--crate-name" && pair[1] == "build_script_build");
+    has_input && !is_query && !is_build_script
+}
+
+/// Runs the real rustc with the given arguments, exiting if it fails
+fn run_rustc(rustc: &str, rustc_args: &[String]) {
+    let status = process::Command::new(rustc)
+        .args(rustc_args)
+        .status()
+        .expect("failed to run rustc");
+    if !status.success() {
+        process::exit(status.code().unwrap_or(1));
+    }
+}
+
 fn main() {
+    let mut args: Vec<String> = env::args().collect();
+
+    // As RUSTC_WORKSPACE_WRAPPER, cargo passes the path to rustc as our first argument.
+    // Cargo only wraps workspace members, so dependencies never reach this driver.
+    let wrapped_rustc = match args.get(1) {
+        Some(arg) if path::Path::new(arg).file_stem() == Some(OsStr::new("rustc")) => {
+            Some(args.remove(1))
+        }
+        _ => None,
+    };
+    if let Some(rustc) = &wrapped_rustc {
+        if !is_analysed_crate(&args[1..]) {
+            run_rustc(rustc, &args[1..]);
+            return;
+        }
+    }
+
     let mut compiler_args = Vec::new();
     let mut callback_args = Vec::new();
-    for arg in std::env::args() {
+    for arg in args.iter().cloned() {
         if arg.starts_with("--analysis") {
             callback_args.push(arg);
         } else {
             compiler_args.push(arg);
         }
     }
-    compiler_args.push("-Zpolonius".to_owned());
+    if let Ok(forwarded) = env::var(ANALYSIS_ARGS_ENV) {
+        callback_args.extend(
+            forwarded
+                .split('\x1f')
+                .filter(|arg| !arg.is_empty())
+                .map(str::to_owned),
+        );
+    }
+    if let Ok(path) = env::var(ANALYSIS_ARGS_FILE_ENV) {
+        let forwarded = fs::read_to_string(&path).unwrap_or_else(|err| {
+            eprintln!("[error] could not read the options in {}: {}", path, err);
+            process::exit(1)
+        });
+        callback_args.extend(
+            forwarded
+                .lines()
+                .filter(|arg| !arg.is_empty())
+                .map(str::to_owned),
+        );
+    }
+    let rustc_args = compiler_args[1..].to_vec();
+
+    let analysis_config = config::AnalysisConfig::parse(&callback_args).unwrap_or_else(|err| {
+        eprintln!("[error] {}", err);
+        process::exit(1)
+    });
+    let engine = analysis_config.engine;
+    config::init(analysis_config);
+
+    if config::config().mode == config::AnalysisMode::Differential {
+        // Each test gets its own child compilations; this process compiles nothing itself
+        differential::run(&rustc_args);
+        if let Some(rustc) = &wrapped_rustc {
+            run_rustc(rustc, &rustc_args);
+        }
+        return;
+    }
+
+    // rustc_borrowck reads the Polonius algorithm from the environment
+    env::set_var("POLONIUS_ALGORITHM", engine.polonius_algorithm());
+    compiler_args.extend(engine.compiler_args());
     compiler_args.push("-Zalways-encode-mir".to_owned());
     compiler_args.push("-Zcrate-attr=feature(register_tool)".to_owned());
     compiler_args.push("-Zcrate-attr=register_tool(analyzer)".to_owned());
 
-    // Just here for visual testing
-    compiler_args.push("-Zdump-mir=all".to_owned());
-    compiler_args.push("-Zdump-mir-dataflow".to_owned());
+    // Only dumped when asked for; child compilations are not asked, so never dump
+    if let Some(dir) = &config::config().dump_mir_dir {
+        compiler_args.push("-Zdump-mir=all".to_owned());
+        compiler_args.push("-Zdump-mir-dataflow".to_owned());
+        compiler_args.push(format!("-Zdump-mir-dir={}", dir.display()));
+    }
 
     let mut callbacks = OurCompilerCalls {
         args: callback_args,
+        crate_name: None,
     };
 
-    rustc_driver::RunCompiler::new(&compiler_args, &mut callbacks)
-        .run()
-        .expect("compiler returned an err");
+    let result = rustc_driver::RunCompiler::new(&compiler_args, &mut callbacks).run();
+
+    // Rejected tests fail the analysis, which stops it before after_analysis, so the report
+    //  is taken here whether or not the compilation succeeded
+    let report = match config::config().mode {
+        config::AnalysisMode::Tests | config::AnalysisMode::Instrument => {
+            callbacks.crate_name.take().map(|crate_name| {
+                let mut report = report::take_report(crate_name);
+                runner::complete_report(&mut report, &rustc_args, engine);
+                report
+            })
+        }
+        config::AnalysisMode::Differential
+        | config::AnalysisMode::Capabilities
+        | config::AnalysisMode::Search => None,
+    };
+    if let Some(report) = &report {
+        report.print();
+        if let Some(path) = &config::config().report {
+            report.write(path);
+        }
+    }
+
+    // The compilation above only recorded the bodies; every test gets a child compilation
+    match config::config().mode {
+        config::AnalysisMode::Capabilities => capabilities::run(&rustc_args),
+        config::AnalysisMode::Search => search::run(&rustc_args),
+        config::AnalysisMode::Tests
+        | config::AnalysisMode::Differential
+        | config::AnalysisMode::Instrument => {}
+    }
+
+    match &wrapped_rustc {
+        // Rejected tests show up as compiler errors, which must not fail the user's build.
+        // Analysis stops before codegen, so the real rustc produces the artifacts cargo expects.
+        Some(rustc) => run_rustc(rustc, &rustc_args),
+        // The errors of rejected bodies are the answer; any other error is a failure
+        None => {
+            if result.is_err() && !report.is_some_and(|report| report.has_rejections()) {
+                process::exit(1);
+            }
+        }
+    }
 }
@@ -0,0 +1,302 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reborrow graphs: at each location, which places are blocked by which borrows.
+//!
+//! An edge runs from the place each live loan borrows to the place its borrow was assigned
+//! to, listing the live locals which hold the loan at that point, i.e. those whose type
+//! mentions an origin which contains it. A reborrow of `(*f)` is linked to the loans `f` held
+//! where it was taken, i.e. the loans it reborrows through.
+//!
+//! Locations are given in the unmodified MIR where the code borrowck checked has one there,
+//! and in the body borrowck checked otherwise. Loans forged code takes are marked, and drawn
+//! dashed.
+
+use crate::facts;
+use crate::forged_ids;
+use polonius_engine::{Algorithm, FactTypes, Output};
+use rustc_borrowck::consumers::{BodyWithBorrowckFacts, RichLocation, RustcFacts};
+use rustc_hash::{FxHashMap, FxHashSet};
+use rustc_middle::mir::{Local, Location, ProjectionElem, VarDebugInfoContents};
+use rustc_middle::ty::{self, TyCtxt};
+use rustc_span::def_id::LocalDefId;
+use serde::Serialize;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+type Point = <RustcFacts as FactTypes>::Point;
+type Loan = <RustcFacts as FactTypes>::Loan;
+
+#[derive(Debug, Serialize)]
+struct LoanEdge {
+    loan: String,
+    /// The place the loan borrows
+    borrowed: String,
+    /// The place the borrow was assigned to
+    borrower: String,
+    /// Live locals holding the loan
+    held_by: Vec<String>,
+    /// For a reborrow, the reference it reborrows through, and the loans that held there
+    reborrowed_through: Option<String>,
+    reborrowed_loans: Vec<String>,
+    /// Where the loan was taken
+    created_at: String,
+    /// Whether forged code took the loan, rather than the original code
+    forged: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct LocationGraph {
+    location: String,
+    /// Set when the location is in the body borrowck checked, having none in the unmodified MIR
+    checked_body_only: bool,
+    edges: Vec<LoanEdge>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReborrowGraph {
+    function: String,
+    locations: Vec<LocationGraph>,
+}
+
+/// Which live locals hold which loans, from the Polonius facts of a body
+pub(crate) struct LoanHolders {
+    pub output: Output<RustcFacts>,
+    /// Origins in the type of every local which has any
+    local_origins: Vec<(Local, FxHashSet<ty::RegionVid>)>,
+    /// Locals holding user variables, named after them
+    names: FxHashMap<Local, String>,
+}
+
+impl LoanHolders {
+    pub fn new<'tcx>(tcx: TyCtxt<'tcx>, facts: &BodyWithBorrowckFacts<'tcx>) -> Option<Self> {
+        // rustc does not keep origin_contains_loan_at, so compute our own
+        let output = Output::compute(facts.input_facts.as_ref()?, Algorithm::Naive, true);
+
+        let local_origins = facts
+            .body
+            .local_decls
+            .iter_enumerated()
+            .map(|(local, decl)| {
+                let mut origins = FxHashSet::default();
+                tcx.for_each_free_region(&decl.ty, |region| {
+                    if let ty::ReVar(vid) = region.kind() {
+                        origins.insert(vid);
+                    }
+                });
+                (local, origins)
+            })
+            .filter(|(_, origins)| !origins.is_empty())
+            .collect();
+
+        let names = facts
+            .body
+            .var_debug_info
+            .iter()
+            .filter_map(|info| match &info.value {
+                VarDebugInfoContents::Place(place) => {
+                    Some((place.as_local()?, format!("{} ({:?})", info.name, place)))
+                }
+                _ => None,
+            })
+            .collect();
+
+        Some(LoanHolders {
+            output,
+            local_origins,
+            names,
+        })
+    }
+
+    /// Every live loan at a point, with each live local holding it
+    pub fn at(&self, point: Point) -> Vec<(Loan, Local)> {
+        let (Some(origin_loans), Some(live_locals), Some(live_loans)) = (
+            self.output.origin_contains_loan_at.get(&point),
+            self.output.var_live_on_entry.get(&point),
+            self.output.loan_live_at.get(&point),
+        ) else {
+            return vec![];
+        };
+        let mut held = vec![];
+        for (local, origins) in self.local_origins.iter() {
+            if !live_locals.contains(local) {
+                continue;
+            }
+            let loans: FxHashSet<_> = origins
+                .iter()
+                .filter_map(|origin| origin_loans.get(origin))
+                .flatten()
+                .filter(|loan| live_loans.contains(*loan))
+                .collect();
+            held.extend(loans.into_iter().map(|loan| (*loan, *local)));
+        }
+        held
+    }
+
+    /// The local's user variable and the local, or just the local
+    pub fn local_name(&self, local: Local) -> String {
+        self.names
+            .get(&local)
+            .cloned()
+            .unwrap_or_else(|| format!("{:?}", local))
+    }
+}
+
+fn extract<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    def_id: LocalDefId,
+    facts: &BodyWithBorrowckFacts<'tcx>,
+    original_locations: &FxHashMap<Location, Location>,
+) -> Vec<LocationGraph> {
+    let (Some(holders), Some(location_table)) =
+        (LoanHolders::new(tcx, facts), &facts.location_table)
+    else {
+        return vec![];
+    };
+    let show = |location: Location| match original_locations.get(&location) {
+        Some(original) => (format!("{:?}", original), false),
+        None => (format!("{:?}", location), true),
+    };
+
+    let mut graphs = vec![];
+    for point in location_table.all_points() {
+        let RichLocation::Start(location) = location_table.to_location(point) else {
+            continue;
+        };
+        let held = holders.at(point);
+        let mut loans: Vec<_> = held.iter().map(|(loan, _)| *loan).collect();
+        loans.sort();
+        loans.dedup();
+        let edges = loans
+            .into_iter()
+            .map(|loan| {
+                let borrow = &facts.borrow_set[loan];
+                // A reborrow reaches its place through the reference of its last deref
+                let reborrowed_through = borrow
+                    .borrowed_place
+                    .iter_projections()
+                    .filter(|(_, elem)| matches!(elem, ProjectionElem::Deref))
+                    .last()
+                    .map(|(reference, _)| reference);
+                let reborrowed_loans = match reborrowed_through {
+                    Some(reference) => {
+                        let reserved = location_table.start_index(borrow.reserve_location);
+                        let mut loans: Vec<_> = holders
+                            .at(reserved)
+                            .into_iter()
+                            .filter(|(_, local)| *local == reference.local)
+                            .map(|(loan, _)| format!("{:?}", loan))
+                            .collect();
+                        loans.sort();
+                        loans.dedup();
+                        loans
+                    }
+                    None => vec![],
+                };
+                LoanEdge {
+                    loan: format!("{:?}", loan),
+                    borrowed: format!("{:?}", borrow.borrowed_place),
+                    borrower: format!("{:?}", borrow.assigned_place),
+                    held_by: held
+                        .iter()
+                        .filter(|(held_loan, _)| *held_loan == loan)
+                        .map(|(_, local)| holders.local_name(*local))
+                        .collect(),
+                    reborrowed_through: reborrowed_through
+                        .map(|reference| format!("{:?}", reference)),
+                    reborrowed_loans,
+                    created_at: show(borrow.reserve_location).0,
+                    forged: forged_ids::is_forged_region(def_id, borrow.region),
+                }
+            })
+            .collect::<Vec<_>>();
+        if !edges.is_empty() {
+            let (location, checked_body_only) = show(location);
+            graphs.push(LocationGraph {
+                location,
+                checked_body_only,
+                edges,
+            });
+        }
+    }
+    graphs
+}
+
+fn write_dot(path: &Path, graph: &ReborrowGraph) {
+    let mut file = fs::File::create(path).expect("could not create the DOT file");
+    let mut dot = format!("digraph \"{}\" {{\n", graph.function);
+    for (index, location) in graph.locations.iter().enumerate() {
+        dot += &format!("  subgraph cluster_{} {{\n", index);
+        dot += &format!("    label = \"{}\";\n", location.location);
+        for edge in location.edges.iter() {
+            // Node names are scoped by location, as the same place appears in many clusters
+            dot += &format!(
+                "    \"{0}/{1}\" [label = \"{1}\"];\n    \"{0}/{2}\" [label = \"{2}\"];\n",
+                location.location, edge.borrowed, edge.borrower
+            );
+            dot += &format!(
+                "    \"{}/{}\" -> \"{}/{}\" [label = \"{} @ {}\"{}];\n",
+                location.location,
+                edge.borrowed,
+                location.location,
+                edge.borrower,
+                edge.loan,
+                edge.created_at,
+                if edge.forged { ", style = dashed" } else { "" }
+            );
+            // A reborrow continues the chain from the reference it reborrows through
+            if let Some(reference) = &edge.reborrowed_through {
+                dot += &format!(
+                    "    \"{0}/{1}\" [label = \"{1}\"];\n",
+                    location.location, reference
+                );
+                dot += &format!(
+                    "    \"{}/{}\" -> \"{}/{}\" [label = \"{}\", style = dotted];\n",
+                    location.location,
+                    reference,
+                    location.location,
+                    edge.borrowed,
+                    edge.reborrowed_loans.join(", ")
+                );
+            }
+        }
+        dot += "  }\n";
+    }
+    dot += "}\n";
+    file.write_all(dot.as_bytes())
+        .expect("could not write the DOT file");
+}
+
+/// Writes the reborrow graph of a body to `<dir>/<function>.json` and `<dir>/<function>.dot`
+pub(crate) fn export<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    def_id: LocalDefId,
+    dir: &Path,
+    function: &str,
+    facts: &BodyWithBorrowckFacts<'tcx>,
+    original_locations: &FxHashMap<Location, Location>,
+) {
+    let graph = ReborrowGraph {
+        function: function.to_owned(),
+        locations: extract(tcx, def_id, facts, original_locations),
+    };
+    if graph.locations.is_empty() {
+        return;
+    }
+    fs::create_dir_all(dir).expect("could not create the reborrow graph directory");
+    let stem = facts::function_file_name(function);
+
+    let file = fs::File::create(dir.join(format!("{}.json", stem)))
+        .expect("could not create the reborrow graph file");
+    serde_json::to_writer_pretty(file, &graph).expect("could not write the reborrow graph");
+    write_dot(&dir.join(format!("{}.dot", stem)), &graph);
+    println!(
+        "[log] reborrow graph of {} written to {}",
+        function,
+        dir.display()
+    );
+}
@@ -0,0 +1,198 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Output report: the verdict of every injected test, per function.
+
+use crate::config::{self, BorrowckEngine};
+use crate::explain::BlockingLoan;
+use crate::instrument::Coverage;
+use crate::TestKind;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+
+/// Whether the borrow checker accepted a test
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Verdict {
+    Accepted,
+    Rejected,
+    /// Not run, as the place is uninitialised on some path to the test, so the test would be
+    /// rejected whatever the loans
+    Uninitialised,
+}
+
+impl Verdict {
+    pub fn from_rejected(rejected: bool) -> Self {
+        if rejected {
+            Verdict::Rejected
+        } else {
+            Verdict::Accepted
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct TestReport {
+    /// Index of the test among those planned for its function, in the order given
+    pub id: usize,
+    pub kind: TestKind,
+    /// Location in the unmodified MIR
+    pub location: String,
+    /// Where a two-phase activation test activates its borrow
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub activation: Option<String>,
+    pub place: String,
+    /// Place whose region a borrow test must outlive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outlives: Option<String>,
+    /// Verdict of the selected engine if the test is alone in its function; otherwise the
+    /// engine cannot tell the tests apart, and this is the Polonius verdict, which the test
+    /// was only packed with others to get if the facts express every error it can cause, or
+    /// every one but the place errors a single compilation of its place and kind finds
+    pub verdict: Verdict,
+    /// Verdict derived from the Polonius output facts for the rewritten body
+    pub polonius_verdict: Verdict,
+    /// Set when the selected engine is NLL, the test is alone in its function, and NLL's
+    /// verdict differs from the Polonius facts', for tests whose errors the facts express
+    pub differs_from_polonius_facts: bool,
+    /// Loans which the Polonius facts say block a rejected test
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocked_by: Vec<BlockingLoan>,
+    /// Locals carrying regions live at the test's location: the only ones whose loans can
+    /// make the verdict depend on where the test is
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub live_references: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct FunctionReport {
+    pub function: String,
+    /// Verdict of the selected engine on the rewritten body as a whole
+    pub verdict: Verdict,
+    pub tests: Vec<TestReport>,
+    /// What the tests cover, if instrument mode chose them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coverage: Option<Coverage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Report {
+    pub crate_name: String,
+    pub engine: BorrowckEngine,
+    pub functions: Vec<FunctionReport>,
+}
+
+/// Functions reported so far in this compilation
+static FUNCTIONS: LazyLock<Mutex<Vec<FunctionReport>>> = LazyLock::new(Default::default);
+
+pub(crate) fn add_function(function: FunctionReport) {
+    FUNCTIONS.lock().unwrap().push(function);
+}
+
+/// Collects everything reported during this compilation of the named crate
+pub(crate) fn take_report(crate_name: String) -> Report {
+    let mut functions = std::mem::take(&mut *FUNCTIONS.lock().unwrap());
+    functions.sort_by(|a, b| a.function.cmp(&b.function));
+    Report {
+        crate_name,
+        engine: config::config().engine,
+        functions,
+    }
+}
+
+impl Report {
+    /// Was any rewritten body rejected? The errors the compilation emitted are then answers
+    pub fn has_rejections(&self) -> bool {
+        self.functions
+            .iter()
+            .any(|function| function.verdict == Verdict::Rejected)
+    }
+
+    /// Adds a test which was compiled apart from the rest of its function's, keeping the
+    /// function's tests in order
+    pub fn add_test(&mut self, function: &str, test: TestReport) {
+        let Some(function) = self
+            .functions
+            .iter_mut()
+            .find(|reported| reported.function == function)
+        else {
+            println!("[warn] no report for {}, which test {} belongs to", function, test.id);
+            return;
+        };
+        let index = function.tests.partition_point(|other| other.id < test.id);
+        function.tests.insert(index, test);
+    }
+
+    pub fn print(&self) {
+        println!("[info] borrowck engine: {:?}", self.engine);
+        for function in self.functions.iter() {
+            println!("[info] {}: {:?}", function.function, function.verdict);
+            if let Some(coverage) = &function.coverage {
+                println!(
+                    "[info]     {} tests of {} locals at {} locations, {} left out as uninitialised",
+                    coverage.tests,
+                    coverage.locals.len(),
+                    coverage.locations,
+                    coverage.uninitialised.len()
+                );
+            }
+            for test in function.tests.iter() {
+                let outlives = match &test.outlives {
+                    Some(target) => format!(" outliving {}", target),
+                    None => String::new(),
+                };
+                let activation = match &test.activation {
+                    Some(activation) => format!(" activated at {}", activation),
+                    None => String::new(),
+                };
+                println!(
+                    "[info]     {:?} of {}{} at {}{}: {:?}",
+                    test.kind, test.place, outlives, test.location, activation, test.verdict
+                );
+                if !test.live_references.is_empty() {
+                    println!(
+                        "[info]         live references: {}",
+                        test.live_references.join(", ")
+                    );
+                }
+                for loan in test.blocked_by.iter() {
+                    println!(
+                        "[info]         blocked by {} of {}, taken at {} ({}), held by {}",
+                        loan.loan,
+                        loan.borrowed,
+                        loan.created_at.as_deref().unwrap_or("forged code"),
+                        loan.span,
+                        if loan.held_by.is_empty() {
+                            "no live local".to_owned()
+                        } else {
+                            loan.held_by.join(", ")
+                        }
+                    );
+                }
+                if test.differs_from_polonius_facts {
+                    println!(
+                        "[warn]     {:?} differs from the Polonius facts, which say {:?}",
+                        self.engine, test.polonius_verdict
+                    );
+                }
+            }
+        }
+    }
+
+    /// Writes the report as JSON, into `<path>/<crate>.json` if path is a directory
+    pub fn write(&self, path: &Path) {
+        let path = if path.is_dir() {
+            path.join(format!("{}.json", self.crate_name))
+        } else {
+            path.to_owned()
+        };
+        let file = fs::File::create(&path).expect("could not create the report file");
+        serde_json::to_writer_pretty(file, self).expect("could not write the report");
+        println!("[info] report written to {}", path.display());
+    }
+}
@@ -0,0 +1,218 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Runs the driver again in a child process, for modes which need several compilations
+//! of the same crate with different rewrites.
+//!
+//! Each child reads its options from a temporary file, as a batch of tests can be too long
+//! for a command line. It writes its report to another and its diagnostics to stderr as
+//! JSON, so that rejected tests can be shown with the errors borrowck gave for them.
+
+use crate::config::{self, BorrowckEngine, TestSpec};
+use crate::report::{Report, TestReport, Verdict};
+use crate::verdicts;
+use crate::{ANALYSIS_ARGS_ENV, ANALYSIS_ARGS_FILE_ENV};
+use rustc_hash::FxHashMap;
+use std::env;
+use std::fs;
+use std::process::{self, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// What a child compilation produced
+#[derive(Debug)]
+pub(crate) struct ChildOutcome {
+    /// None if the child crashed before writing its report
+    pub report: Option<Report>,
+    /// Rendered errors the child emitted
+    pub diagnostics: Vec<String>,
+}
+
+static NEXT_CHILD: AtomicUsize = AtomicUsize::new(0);
+
+/// Rendered text of a JSON diagnostic line, if it is an error about the code
+fn rendered_error(line: &str) -> Option<String> {
+    let diagnostic: serde_json::Value = serde_json::from_str(line).ok()?;
+    if diagnostic["level"] != "error" {
+        return None;
+    }
+    let message = diagnostic["message"].as_str()?;
+    if message.starts_with("aborting due to") {
+        return None;
+    }
+    Some(
+        diagnostic["rendered"]
+            .as_str()
+            .unwrap_or(message)
+            .to_owned(),
+    )
+}
+
+/// Compiles the crate again with the given `--analysis-*` options, on top of those shaping
+/// the rewrite, which are the parent's
+///
+/// `rustc_args` are the arguments of the current compilation, without the program name.
+pub(crate) fn run_child(rustc_args: &[String], analysis_args: &[String]) -> ChildOutcome {
+    let child = NEXT_CHILD.fetch_add(1, Ordering::Relaxed);
+    let report_path = env::temp_dir().join(format!("mir-rewrite-{}-{}.json", process::id(), child));
+    let args_path = env::temp_dir().join(format!("mir-rewrite-{}-{}.args", process::id(), child));
+    let mut child_args = config::config().rewrite_args();
+    child_args.extend(analysis_args.iter().cloned());
+    child_args.push(format!("--analysis-report={}", report_path.display()));
+    fs::write(&args_path, child_args.join("\n")).expect("could not write the child's options");
+
+    // We need JSON diagnostics whatever the parent was asked for
+    let rustc_args = rustc_args
+        .iter()
+        .filter(|arg| !arg.starts_with("--error-format") && !arg.starts_with("--json"));
+
+    let output = process::Command::new(env::current_exe().expect("driver path is unavailable"))
+        .args(rustc_args)
+        .arg("--error-format=json")
+        // The parent's forwarded options would make the child run our mode again
+        .env_remove(ANALYSIS_ARGS_ENV)
+        .env(ANALYSIS_ARGS_FILE_ENV, &args_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("failed to run the driver");
+
+    let report = fs::read_to_string(&report_path)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok());
+    let _ = fs::remove_file(&report_path);
+    let _ = fs::remove_file(&args_path);
+
+    let diagnostics = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .filter_map(rendered_error)
+        .collect();
+
+    ChildOutcome {
+        report,
+        diagnostics,
+    }
+}
+
+/// Compiles the crate again with the given tests, under the given engine
+///
+/// Tests of the same function are packed into one body, as far as their verdicts can be
+/// told apart there. The report of each is None if the child crashed before reporting it,
+/// which is reported as an error.
+pub(crate) fn run_tests(
+    rustc_args: &[String],
+    specs: &[TestSpec],
+    engine: BorrowckEngine,
+) -> Vec<Option<TestReport>> {
+    let mut analysis_args = vec![format!("--analysis-engine={}", engine)];
+    analysis_args.extend(specs.iter().map(|spec| format!("--analysis-test={}", spec)));
+    let ChildOutcome {
+        report,
+        diagnostics,
+    } = run_child(rustc_args, &analysis_args);
+    if report.is_none() {
+        println!(
+            "[error] the child compilation of {} tests produced no report",
+            specs.len()
+        );
+        for diagnostic in diagnostics.iter() {
+            println!("{}", diagnostic);
+        }
+    }
+
+    let mut reported: FxHashMap<(String, usize), TestReport> = report
+        .into_iter()
+        .flat_map(|report| report.functions)
+        .flat_map(|function| {
+            let name = function.function;
+            function
+                .tests
+                .into_iter()
+                .map(move |test| ((name.clone(), test.id), test))
+        })
+        .collect();
+
+    // The child numbers each function's tests in the order they were given
+    let mut next_id: FxHashMap<&str, usize> = Default::default();
+    specs
+        .iter()
+        .map(|spec| {
+            let id = next_id.entry(&spec.function).or_default();
+            *id += 1;
+            reported.remove(&(spec.function.clone(), *id - 1))
+        })
+        .collect()
+}
+
+/// Compiles the crate again with a single test, under the given engine
+pub(crate) fn run_test(
+    rustc_args: &[String],
+    spec: &TestSpec,
+    engine: BorrowckEngine,
+) -> Option<TestReport> {
+    run_tests(rustc_args, std::slice::from_ref(spec), engine).pop().flatten()
+}
+
+/// Completes the report of the current compilation with the verdicts it could not give
+///
+/// Tests which could not share their body are compiled on their own. For each place check,
+/// one test accepted in the packed body is compiled on its own; if that rejects it, the place
+/// has an error the facts do not express, which rejects every test of the check.
+pub(crate) fn complete_report(report: &mut Report, rustc_args: &[String], engine: BorrowckEngine) {
+    for isolated in verdicts::take_isolated_tests() {
+        match run_test(rustc_args, &isolated.spec, engine) {
+            Some(test) => report.add_test(
+                &isolated.function,
+                TestReport {
+                    id: isolated.id,
+                    ..test
+                },
+            ),
+            None => println!(
+                "[error] {} compiled on its own reported no verdict",
+                isolated.spec
+            ),
+        }
+    }
+
+    for check in verdicts::take_place_checks() {
+        let Some(function) = report
+            .functions
+            .iter_mut()
+            .find(|reported| reported.function == check.function)
+        else {
+            continue;
+        };
+        let accepted: Vec<&mut TestReport> = function
+            .tests
+            .iter_mut()
+            .filter(|test| {
+                test.verdict == Verdict::Accepted
+                    && check.tests.iter().any(|(id, _)| *id == test.id)
+            })
+            .collect();
+        let Some(first) = accepted.first() else {
+            continue;
+        };
+        let (_, location) = check
+            .tests
+            .iter()
+            .find(|(id, _)| *id == first.id)
+            .unwrap();
+        let spec = TestSpec {
+            location: *location,
+            ..check.spec.clone()
+        };
+        match run_test(rustc_args, &spec, engine) {
+            Some(test) if test.verdict == Verdict::Rejected => {
+                for test in accepted {
+                    test.verdict = Verdict::Rejected;
+                }
+            }
+            Some(_) => {}
+            None => println!("[error] {} compiled on its own reported no verdict", spec),
+        }
+    }
+}
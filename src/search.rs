@@ -0,0 +1,227 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Search mode: for each given test, the frontier of locations from which it first succeeds,
+//! searching forward along the CFG from the test's location.
+//!
+//! A first compilation only records the shape of the CFG. Each probe is then a child
+//! compilation with the test at one location. Within a block, a test which succeeds at
+//! some statement is assumed to succeed at every later one, e.g. because the borrow
+//! blocking it has expired; the first success is found by binary search. A block whose
+//! terminator still rejects the test passes the search on to its successors. Blocks are
+//! searched once from each location the search enters them at, so a loop back into the
+//! test's block searches it again from its start.
+
+use crate::config::{self, TestSpec};
+use crate::report::Verdict;
+use crate::runner;
+use rustc_hash::{FxHashMap, FxHashSet};
+use rustc_middle::mir::{BasicBlock, Body, Location};
+use rustc_middle::ty::TyCtxt;
+use rustc_span::def_id::LocalDefId;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs;
+use std::sync::{LazyLock, Mutex};
+
+#[derive(Debug)]
+struct BlockShape {
+    /// The terminator is at this statement index
+    statement_count: usize,
+    successors: Vec<BasicBlock>,
+    is_cleanup: bool,
+}
+
+/// Blocks of the bodies which have tests to search for, by def path
+static BODIES: LazyLock<Mutex<FxHashMap<String, Vec<BlockShape>>>> =
+    LazyLock::new(Default::default);
+
+/// Records the CFG of a body, if any test is searched for in it
+pub(crate) fn record_body<'tcx>(tcx: TyCtxt<'tcx>, def_id: LocalDefId, body: &Body<'tcx>) {
+    let function = tcx.def_path_str(def_id.to_def_id());
+    if config::config().tests_for(&function).next().is_none() {
+        return;
+    }
+    let blocks = body
+        .basic_blocks
+        .iter()
+        .map(|data| BlockShape {
+            statement_count: data.statements.len(),
+            successors: data.terminator().successors().collect(),
+            is_cleanup: data.is_cleanup,
+        })
+        .collect();
+    BODIES.lock().unwrap().insert(function, blocks);
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResult {
+    test: String,
+    /// Earliest locations at which the test succeeds, one per path from its location
+    frontier: Vec<String>,
+    /// Terminators of returning blocks reached without the test ever succeeding
+    never_succeeds_before: Vec<String>,
+    /// Number of child compilations run
+    probes: usize,
+}
+
+struct Search<'a> {
+    rustc_args: &'a [String],
+    spec: &'a TestSpec,
+    probes: FxHashMap<Location, bool>,
+}
+
+impl<'a> Search<'a> {
+    /// Does the test succeed at this location? A probe which reports no verdict is an error,
+    /// which ends the search.
+    fn accepted(&mut self, location: Location) -> Result<bool, String> {
+        if let Some(accepted) = self.probes.get(&location) {
+            return Ok(*accepted);
+        }
+        let spec = TestSpec {
+            location,
+            ..self.spec.clone()
+        };
+        let verdict = runner::run_test(self.rustc_args, &spec, config::config().engine)
+            .map(|test| test.verdict)
+            .ok_or_else(|| format!("probe {} reported no verdict", spec))?;
+        let accepted = verdict == Verdict::Accepted;
+        self.probes.insert(location, accepted);
+        Ok(accepted)
+    }
+
+    /// First statement index in [first, last] at which the test succeeds, given that it
+    /// succeeds at last
+    fn first_accepted(
+        &mut self,
+        block: BasicBlock,
+        first: usize,
+        last: usize,
+    ) -> Result<usize, String> {
+        let (mut low, mut high) = (first, last);
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.accepted(Location {
+                block,
+                statement_index: middle,
+            })? {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        Ok(low)
+    }
+
+    fn run(mut self, blocks: &[BlockShape]) -> Result<SearchResult, String> {
+        let mut frontier = vec![];
+        let mut never_succeeds_before = vec![];
+        let mut visited = FxHashSet::default();
+        let mut queue = VecDeque::from([self.spec.location]);
+
+        while let Some(start) = queue.pop_front() {
+            if !visited.insert(start) {
+                continue;
+            }
+            let shape = &blocks[start.block.index()];
+            let terminator = Location {
+                block: start.block,
+                statement_index: shape.statement_count,
+            };
+            if self.accepted(terminator)? {
+                let statement_index = self.first_accepted(
+                    start.block,
+                    start.statement_index,
+                    terminator.statement_index,
+                )?;
+                frontier.push(Location {
+                    block: start.block,
+                    statement_index,
+                });
+            } else if shape.successors.is_empty() {
+                never_succeeds_before.push(terminator);
+            } else {
+                // Tests cannot be split into cleanup blocks, so unwinding paths are not searched
+                queue.extend(
+                    shape
+                        .successors
+                        .iter()
+                        .filter(|successor| !blocks[successor.index()].is_cleanup)
+                        .map(|successor| successor.start_location()),
+                );
+            }
+        }
+
+        frontier.sort();
+        frontier.dedup();
+        never_succeeds_before.sort();
+        never_succeeds_before.dedup();
+        Ok(SearchResult {
+            test: self.spec.to_string(),
+            frontier: frontier
+                .iter()
+                .map(|location| format!("{:?}", location))
+                .collect(),
+            never_succeeds_before: never_succeeds_before
+                .iter()
+                .map(|location| format!("{:?}", location))
+                .collect(),
+            probes: self.probes.len(),
+        })
+    }
+}
+
+/// Searches for the frontier of every configured test
+pub(crate) fn run(rustc_args: &[String]) {
+    let bodies = std::mem::take(&mut *BODIES.lock().unwrap());
+    let mut results = vec![];
+    for spec in config::config().tests.iter() {
+        let Some(blocks) = bodies.get(&spec.function) else {
+            println!("[warn] no body found for {}", spec);
+            continue;
+        };
+        let search = Search {
+            rustc_args,
+            spec,
+            probes: Default::default(),
+        };
+        let result = match search.run(blocks) {
+            Ok(result) => result,
+            Err(error) => {
+                println!("[error] search for {} failed: {}", spec, error);
+                continue;
+            }
+        };
+        println!(
+            "[info] {} first succeeds at {} ({} probes)",
+            result.test,
+            if result.frontier.is_empty() {
+                "no location".to_owned()
+            } else {
+                result.frontier.join(", ")
+            },
+            result.probes
+        );
+        if !result.never_succeeds_before.is_empty() {
+            println!(
+                "[info]     and never succeeds on paths ending at {}",
+                result.never_succeeds_before.join(", ")
+            );
+        }
+        results.push(result);
+    }
+
+    if let Some(path) = &config::config().report {
+        let path = if path.is_dir() {
+            path.join("search.json")
+        } else {
+            path.to_owned()
+        };
+        let file = fs::File::create(&path).expect("could not create the report file");
+        serde_json::to_writer_pretty(file, &results).expect("could not write the report");
+        println!("[info] report written to {}", path.display());
+    }
+}
@@ -0,0 +1,298 @@
+// © 2023, ETH Zurich
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Borrowck verdicts for the injected tests.
+//!
+//! The `mir_borrowck` override runs the selected engine on the rewritten body and,
+//! alongside it, computes the Polonius output facts. The engine's answer is per body;
+//! the facts let us attribute errors to the forged code of a particular test.
+//!
+//! The facts only stand in for the engine where they are its own, i.e. under Polonius, and
+//! for tests whose every error at their location they express. Other tests are only packed
+//! into a body they would have to themselves; the rest are compiled on their own once the
+//! compilation is done. Errors the facts miss but which only depend on the tested place,
+//! such as mutability errors, are found by compiling one test of the place on its own.
+
+use crate::cleanup::{self, ForgedItems};
+use crate::config::{self, BorrowckEngine, TestSpec};
+use crate::explain;
+use crate::facts;
+use crate::forged_ids;
+use crate::instrument::Coverage;
+use crate::reborrows::{self, LoanHolders};
+use crate::report::{self, FunctionReport, TestReport, Verdict};
+use crate::{FactsCoverage, TestKind};
+use rustc_borrowck::consumers::{self, BodyWithBorrowckFacts, ConsumerOptions, RichLocation};
+use rustc_hash::FxHashMap;
+use rustc_middle::mir::{BorrowCheckResult, Location};
+use rustc_middle::ty::TyCtxt;
+use rustc_span::def_id::LocalDefId;
+use std::sync::{LazyLock, Mutex};
+
+/// A test injected into a body
+#[derive(Debug)]
+pub(crate) struct TestRecord {
+    /// Index of the test among those planned for its body
+    pub id: usize,
+    pub kind: TestKind,
+    /// Location in the unmodified MIR
+    pub location: Location,
+    /// Where a two-phase activation test activates its borrow, in the unmodified MIR
+    pub activation: Option<Location>,
+    pub place: String,
+    /// Place whose region a borrow test must outlive
+    pub outlives: Option<String>,
+    /// Locals forged for this test alone
+    pub forged: ForgedItems,
+    /// Set when the test was not inserted, as its place is uninitialised on some path to it
+    pub uninitialised: bool,
+    /// Locals carrying regions which are live at the test's location in the unmodified MIR
+    pub live_references: Vec<String>,
+}
+
+#[derive(Debug)]
+struct BodyTests {
+    function: String,
+    tests: Vec<TestRecord>,
+    /// What the tests cover, if instrument mode chose them
+    coverage: Option<Coverage>,
+}
+
+/// Tests injected into every rewritten body, waiting for borrowck
+static TESTS: LazyLock<Mutex<FxHashMap<LocalDefId, BodyTests>>> = LazyLock::new(Default::default);
+
+pub(crate) fn register_tests(
+    def_id: LocalDefId,
+    function: String,
+    tests: Vec<TestRecord>,
+    coverage: Option<Coverage>,
+) {
+    TESTS.lock().unwrap().insert(
+        def_id,
+        BodyTests {
+            function,
+            tests,
+            coverage,
+        },
+    );
+}
+
+fn take_tests(def_id: LocalDefId) -> Option<BodyTests> {
+    TESTS.lock().unwrap().remove(&def_id)
+}
+
+/// A test left out of its body, as its verdict could not be told apart from the others'
+#[derive(Debug)]
+pub(crate) struct IsolatedTest {
+    pub function: String,
+    /// Index of the test among those planned for its body
+    pub id: usize,
+    pub spec: TestSpec,
+}
+
+/// Tests waiting to be compiled on their own
+static ISOLATED_TESTS: LazyLock<Mutex<Vec<IsolatedTest>>> = LazyLock::new(Default::default);
+
+pub(crate) fn register_isolated_tests(tests: Vec<IsolatedTest>) {
+    ISOLATED_TESTS.lock().unwrap().extend(tests);
+}
+
+/// Collects the tests left out of every body of this compilation
+pub(crate) fn take_isolated_tests() -> Vec<IsolatedTest> {
+    std::mem::take(&mut *ISOLATED_TESTS.lock().unwrap())
+}
+
+/// Packed tests of one place and kind, whose place errors the Polonius facts do not express
+///
+/// Place errors, like mutability errors, are the same wherever the test is. Compiling one of
+/// the tests on its own, at a location where the facts accept it, tells whether there are any.
+#[derive(Debug)]
+pub(crate) struct PlaceCheck {
+    pub function: String,
+    /// One of the tests; the others only differ in their location
+    pub spec: TestSpec,
+    /// Id and location in the unmodified MIR of every test
+    pub tests: Vec<(usize, Location)>,
+}
+
+/// Place checks waiting for the verdicts of their packed tests
+static PLACE_CHECKS: LazyLock<Mutex<Vec<PlaceCheck>>> = LazyLock::new(Default::default);
+
+pub(crate) fn register_place_checks(checks: Vec<PlaceCheck>) {
+    PLACE_CHECKS.lock().unwrap().extend(checks);
+}
+
+/// Collects the place checks of every body of this compilation
+pub(crate) fn take_place_checks() -> Vec<PlaceCheck> {
+    std::mem::take(&mut *PLACE_CHECKS.lock().unwrap())
+}
+
+/// The errors of the Polonius output, each as the locations (in the borrowck body)
+/// implicated in it: where it occurs, and where each loan it invalidates was taken
+///
+/// A loan taken by forged code can be invalidated by the original code, as when a
+/// two-phase borrow's reservation conflicts with an access before its activation.
+fn polonius_errors(facts: &BodyWithBorrowckFacts<'_>) -> Vec<Vec<Location>> {
+    let (Some(output), Some(location_table)) = (&facts.output_facts, &facts.location_table) else {
+        return vec![];
+    };
+    let to_location = |point| match location_table.to_location(point) {
+        RichLocation::Start(location) | RichLocation::Mid(location) => location,
+    };
+    let loan_errors = output.errors.iter().map(|(point, loans)| {
+        std::iter::once(to_location(*point))
+            .chain(loans.iter().map(|loan| facts.borrow_set[*loan].reserve_location))
+            .collect()
+    });
+    let other_errors = output
+        .move_errors
+        .keys()
+        .chain(output.subset_errors.keys())
+        .map(|point| vec![to_location(*point)]);
+    loan_errors.chain(other_errors).collect()
+}
+
+#[allow(clippy::needless_lifetimes)]
+pub(crate) fn mir_borrowck<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    def_id: LocalDefId,
+) -> &'tcx BorrowCheckResult<'tcx> {
+    let mir_borrowck_ptr = rustc_interface::DEFAULT_QUERY_PROVIDERS.mir_borrowck;
+    let body_tests = take_tests(def_id);
+    let reborrow_graph_dir = &config::config().reborrow_graph_dir;
+    if body_tests.is_none() && reborrow_graph_dir.is_none() {
+        return mir_borrowck_ptr(tcx, def_id);
+    }
+
+    // Polonius output is computed for consumers whatever the engine. Any errors this
+    //  emits are emitted again by the real borrowck below, and deduplicated by rustc.
+    let facts =
+        consumers::get_body_with_borrowck_facts(tcx, def_id, ConsumerOptions::PoloniusOutputFacts);
+    let result = mir_borrowck_ptr(tcx, def_id);
+    cleanup::with_forged_items(def_id, |forged| {
+        forged_ids::record_forged_regions(tcx, def_id, &facts, forged)
+    });
+    // Where the code borrowck checked is in the unmodified MIR, for the reborrow graph and to
+    //  explain rejections with
+    let original_locations = explain::take_block_markers(def_id)
+        .and_then(|markers| {
+            cleanup::with_forged_items(def_id, |forged| {
+                explain::locate_original_locations(&facts.body, forged, &markers)
+            })
+        })
+        .unwrap_or_default();
+
+    if let Some(dir) = reborrow_graph_dir {
+        let function = tcx.def_path_str(def_id.to_def_id());
+        reborrows::export(tcx, def_id, dir, &function, &facts, &original_locations);
+    }
+    let Some(body_tests) = body_tests else {
+        return result;
+    };
+
+    if let Some(dir) = &config::config().facts_dir {
+        cleanup::with_forged_items(def_id, |forged| {
+            facts::export(def_id, dir, &body_tests.function, &facts, forged)
+        });
+    }
+
+    let verdict = Verdict::from_rejected(result.tainted_by_errors.is_some());
+    let errors = polonius_errors(&facts);
+    let owns_error = |forged: &ForgedItems, error: &[Location]| {
+        error
+            .iter()
+            .any(|location| forged.owns(&facts.body, *location))
+    };
+    // Errors no test owns arise in approximators, ghost code or the original code. They count
+    //  against every test, as the engine's errors do against a test alone in its body.
+    let unowned_errors = errors.iter().any(|error| {
+        !body_tests
+            .tests
+            .iter()
+            .any(|test| owns_error(&test.forged, error))
+    });
+    // A test alone in the body gets the engine's verdict; planning only packed tests whose
+    //  verdicts the facts can tell apart
+    let alone = body_tests
+        .tests
+        .iter()
+        .filter(|test| !test.uninitialised)
+        .count()
+        == 1;
+    // Only needed to explain rejections
+    let holders = if errors.is_empty() {
+        None
+    } else {
+        LoanHolders::new(tcx, &facts)
+    };
+    let tests = body_tests
+        .tests
+        .into_iter()
+        .map(|test| {
+            if test.uninitialised {
+                return TestReport {
+                    id: test.id,
+                    kind: test.kind,
+                    location: format!("{:?}", test.location),
+                    activation: test.activation.map(|location| format!("{:?}", location)),
+                    place: test.place,
+                    outlives: test.outlives,
+                    verdict: Verdict::Uninitialised,
+                    polonius_verdict: Verdict::Uninitialised,
+                    differs_from_polonius_facts: false,
+                    blocked_by: vec![],
+                    live_references: test.live_references,
+                };
+            }
+            let polonius_verdict = Verdict::from_rejected(
+                unowned_errors || errors.iter().any(|error| owns_error(&test.forged, error)),
+            );
+            let blocked_by = match (&holders, polonius_verdict) {
+                (Some(holders), Verdict::Rejected) => explain::blocking_loans(
+                    tcx,
+                    &facts,
+                    holders,
+                    &test.forged,
+                    &original_locations,
+                ),
+                _ => vec![],
+            };
+            // The facts are Polonius's own, so under Polonius they can only differ from the
+            //  engine where they miss an error class. Under NLL, a verdict on the test alone
+            //  can be compared with them for the tests whose errors they express.
+            let differs_from_polonius_facts = config::config().engine == BorrowckEngine::Nll
+                && alone
+                && test.kind.facts_coverage() == FactsCoverage::Complete
+                && verdict != polonius_verdict;
+            let test_verdict = if alone {
+                verdict
+            } else {
+                polonius_verdict
+            };
+            TestReport {
+                id: test.id,
+                kind: test.kind,
+                location: format!("{:?}", test.location),
+                activation: test.activation.map(|location| format!("{:?}", location)),
+                place: test.place,
+                outlives: test.outlives,
+                verdict: test_verdict,
+                polonius_verdict,
+                differs_from_polonius_facts,
+                blocked_by,
+                live_references: test.live_references,
+            }
+        })
+        .collect();
+
+    report::add_function(FunctionReport {
+        function: body_tests.function,
+        verdict,
+        tests,
+        coverage: body_tests.coverage,
+    });
+    result
+}